@@ -0,0 +1,70 @@
+//! Version and build information for the About dialog and bug reports.
+
+use tauri::AppHandle;
+use windows::core::{w, PCWSTR};
+use windows::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD, RRF_RT_REG_SZ};
+
+use crate::logging::{log, LogLevel};
+
+const CURRENT_VERSION_KEY: PCWSTR = w!("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion");
+
+#[derive(Clone, serde::Serialize)]
+pub struct AppInfo {
+  pub version: String,
+  /// "debug" or "release".
+  pub build_profile: String,
+  /// Windows version and build, e.g. "10.0.22631.3447", or `None` if it can't be read.
+  pub os_build: Option<String>
+}
+
+pub fn read_app_info(app_handle: &AppHandle) -> AppInfo {
+  let os_build = match read_os_build() {
+    Ok(os_build) => Some(os_build),
+    Err(err) => {
+      log(LogLevel::Debug, format!("Couldn't read OS build: {err}"));
+      None
+    }
+  };
+  AppInfo {
+    version: app_handle.package_info().version.to_string(),
+    build_profile: match cfg!(debug_assertions) {
+      true => "debug",
+      false => "release"
+    }.to_string(),
+    os_build
+  }
+}
+
+/// Reads the OS version from the registry, as `GetVersionEx` reports the version the app is manifested for
+/// rather than the one that's running.
+fn read_os_build() -> Result<String, String> {
+  let major = read_dword(w!("CurrentMajorVersionNumber"))?;
+  let minor = read_dword(w!("CurrentMinorVersionNumber"))?;
+  let build = read_string(w!("CurrentBuildNumber"))?;
+  // Missing on builds from before Windows 10.
+  let revision = read_dword(w!("UBR")).unwrap_or(0);
+  Ok(format!("{major}.{minor}.{build}.{revision}"))
+}
+
+fn read_dword(name: PCWSTR) -> Result<u32, String> {
+  let mut value = 0u32;
+  let mut size = std::mem::size_of::<u32>() as u32;
+  let data = Some(&mut value as *mut u32 as *mut _);
+  let result = unsafe {
+    RegGetValueW(HKEY_LOCAL_MACHINE, CURRENT_VERSION_KEY, name, RRF_RT_REG_DWORD, None, data, Some(&mut size))
+  };
+  result.ok().map_err(|err| format!("Couldn't read registry value: {err}"))?;
+  Ok(value)
+}
+
+fn read_string(name: PCWSTR) -> Result<String, String> {
+  let mut buffer = [0u16; 64];
+  let mut size = std::mem::size_of_val(&buffer) as u32;
+  let data = Some(buffer.as_mut_ptr() as *mut _);
+  let result = unsafe {
+    RegGetValueW(HKEY_LOCAL_MACHINE, CURRENT_VERSION_KEY, name, RRF_RT_REG_SZ, None, data, Some(&mut size))
+  };
+  result.ok().map_err(|err| format!("Couldn't read registry value: {err}"))?;
+  let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+  Ok(String::from_utf16_lossy(&buffer[..len]))
+}
@@ -0,0 +1,87 @@
+//! What one poll costs the app itself, measured against the mock devices so driver and COM time is left
+//! out and the numbers are comparable between machines. The timings only run when asked:
+//! `cargo test --release device_cycle -- --ignored --nocapture`. `wasapi_device_cycle` in `wasapi` times
+//! the same device calls on the machine's real devices.
+
+use criterion::{black_box, Criterion};
+use windows::core::GUID;
+
+use crate::audio::{mock, AudioController, AudioDeviceConfig, AudioDeviceInfo};
+
+const DEVICE_COUNT: usize = 32;
+
+fn device_id(index: usize) -> String {
+  format!("{{0.0.0.00000000}}.{{{index:08x}}}")
+}
+
+fn limited_config() -> AudioDeviceConfig {
+  let mut config = AudioDeviceConfig::default();
+  config.render.global_max_volume = 0.5;
+  for index in 0..DEVICE_COUNT {
+    config.render.device_max_volumes.insert(device_id(index), 0.25 + index as f32 / 100.0);
+  }
+  config
+}
+
+fn controller() -> AudioController {
+  for index in 0..DEVICE_COUNT {
+    mock::add_device(&device_id(index), &format!("Speakers {}", index % 4), 0.2);
+  }
+  let mut controller = AudioController::init(limited_config(), GUID::zeroed()).unwrap();
+  controller.update_devices().unwrap();
+  controller
+}
+
+/// The device side of a poll: an update, or a rebuild that reopens every device as every update did before
+/// cached devices were reused, followed by listing the devices for the window.
+fn cycle(controller: &mut AudioController, rebuild: bool) -> Vec<AudioDeviceInfo> {
+  match rebuild {
+    true => controller.reinitialize().unwrap(),
+    false => controller.update_devices().unwrap()
+  };
+  controller.get_devices()
+}
+
+/// How many devices one cycle opens and how many properties it reads.
+fn calls_per_cycle(controller: &mut AudioController, rebuild: bool) -> (u32, u32) {
+  let count = || (mock::count_calls(|device| device.opens), mock::count_calls(|device| device.property_reads));
+  let (opens, property_reads) = count();
+  cycle(controller, rebuild);
+  let (opens_after, property_reads_after) = count();
+  (opens_after - opens, property_reads_after - property_reads)
+}
+
+#[test]
+fn device_updates_only_open_devices_when_rebuilding() {
+  let mut controller = controller();
+  assert_eq!(calls_per_cycle(&mut controller, false).0, 0);
+  assert_eq!(calls_per_cycle(&mut controller, true).0, DEVICE_COUNT as u32);
+}
+
+#[test]
+#[ignore]
+fn device_cycle() {
+  let mut criterion = Criterion::default();
+  let json = serde_json::to_string(&limited_config()).unwrap();
+  criterion.bench_function("parse and validate config", |b| {
+    b.iter(|| {
+      let mut config = AudioDeviceConfig::from_json(black_box(&json)).unwrap();
+      config.validate_and_clamp()
+    })
+  });
+
+  let mut controller = controller();
+  for (name, rebuild) in [("update and list devices", false), ("rebuild and list devices", true)] {
+    let (opens, property_reads) = calls_per_cycle(&mut controller, rebuild);
+    println!("{name}: {opens} device opens and {property_reads} property reads per cycle");
+    criterion.bench_function(name, |b| b.iter(|| cycle(&mut controller, rebuild)));
+  }
+  criterion.bench_function("enforce limits", |b| {
+    b.iter(|| {
+      for device_id in controller.limited_device_ids() {
+        controller.apply_max_volume(&device_id).unwrap();
+      }
+    })
+  });
+  criterion.final_summary();
+}
@@ -0,0 +1,633 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::audio::{DataFlow, DeviceRole, FormFactor, VolumeCurve};
+use crate::logging::LogLevel;
+
+/// The global and per-device limits for one data flow.
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct VolumeLimits {
+  pub global_max_volume: f32,
+  pub device_max_volumes: HashMap<String, f32>
+}
+
+impl Default for VolumeLimits {
+  fn default() -> Self {
+    VolumeLimits {
+      global_max_volume: 1.0,
+      device_max_volumes: HashMap::new()
+    }
+  }
+}
+
+/// A named set of output and input limits to switch to.
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Profile {
+  pub name: String,
+  pub render: VolumeLimits,
+  pub capture: VolumeLimits,
+  /// Devices that activate the profile as soon as all of them are present. Profiles without any are only
+  /// activated by hand.
+  #[serde(default)]
+  pub auto_activate_when_present: Vec<String>
+}
+
+/// Global keyboard shortcuts that nudge the global output limit.
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ShortcutConfig {
+  /// Off by default, as the shortcuts are system-wide and can clash with other apps.
+  pub enabled: bool,
+  /// Accelerator that raises the limit, e.g. "CmdOrCtrl+Alt+Up".
+  pub raise: String,
+  pub lower: String,
+  /// How much each press moves the limit.
+  pub step: f32
+}
+
+impl Default for ShortcutConfig {
+  fn default() -> Self {
+    ShortcutConfig {
+      enabled: false,
+      raise: "CmdOrCtrl+Alt+Up".to_string(),
+      lower: "CmdOrCtrl+Alt+Down".to_string(),
+      step: 0.05
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AudioDeviceConfig {
+  /// Limits for output devices.
+  pub render: VolumeLimits,
+  /// Limits for input devices, kept apart so the global output cap doesn't apply to microphones.
+  pub capture: VolumeLimits,
+  /// Saved limits to switch between. When the devices of several profiles are present, the earliest one
+  /// is activated.
+  pub profiles: Vec<Profile>,
+  /// The profile whose limits were last copied into `render` and `capture`.
+  pub active_profile: Option<String>,
+  /// The profile last activated by hand, which is switched back to once no profile's devices are present.
+  pub manual_profile: Option<String>,
+  pub device_aliases: HashMap<String, String>,
+  /// Default caps for devices of a form factor, used when a device has no limit of its own.
+  pub form_factor_max_volumes: HashMap<FormFactor, f32>,
+  /// Cap applied automatically to headphones and headsets the first time they're seen, if they don't
+  /// have a limit yet.
+  pub new_headphone_default_max: Option<f32>,
+  /// The role whose default device `is_default`, `limit_default_device` and `SortOrder::DefaultFirst` go by.
+  pub default_role: DeviceRole,
+  /// Extra cap for whichever device is the default communications device, e.g. to keep calls quieter.
+  pub comms_max_volume: Option<f32>,
+  /// Executable name of the app whose audio ducks every other app while it's playing, e.g. "Discord.exe".
+  pub duck_priority_process: Option<String>,
+  /// Fraction other apps' session volumes are lowered by while they're ducked.
+  pub duck_amount: f32,
+  /// Every device ID that has ever been enumerated, so defaults for new devices are only applied once.
+  pub seen_device_ids: HashSet<String>,
+  /// Devices whose limits are kept but not enforced.
+  pub limit_disabled_device_ids: HashSet<String>,
+  /// Devices that only obey their own cap and may go above the global limit.
+  pub ignore_global_device_ids: HashSet<String>,
+  /// The only devices the app enforces limits on, or `None` to manage every device. Devices outside the
+  /// set are never touched, whatever the global limit.
+  pub managed_devices: Option<HashSet<String>>,
+  /// Exact volumes devices are held at, overriding their caps.
+  pub device_locked_volumes: HashMap<String, f32>,
+  /// What happens to devices that go over their cap. Devices not listed have their volume lowered.
+  pub device_exceed_policies: HashMap<String, ExceedPolicy>,
+  /// How often limits are enforced on a device, for devices that don't need the global apply interval.
+  /// Intervals shorter than the apply interval have no effect.
+  pub device_poll_intervals_ms: HashMap<String, u64>,
+  pub enforce_only_when_playing: bool,
+  /// Enforce every limit as soon as the app starts, before the window is shown, rather than on the first
+  /// periodic tick.
+  pub apply_all_on_startup: bool,
+  /// Master switch for enforcement. When off, limits are kept but not applied.
+  pub limiting_enabled: bool,
+  /// Keep the window hidden in the tray when the app starts.
+  pub start_minimized: bool,
+  /// Whether the welcome screen still has to be shown. Only freshly created configs start out with it
+  /// set; configs written by versions from before the flag existed read as `false`, so upgrading doesn't
+  /// show it again.
+  #[serde(default)]
+  pub first_run: bool,
+  pub close_behavior: CloseBehavior,
+  /// List disabled devices too. Limits are only ever applied to active devices.
+  pub include_disabled_devices: bool,
+  /// Global output limits offered in the tray menu, in percent.
+  pub global_presets: Vec<u8>,
+  pub mode: LimitMode,
+  /// Show a toast when a limit is enforced.
+  pub notify_on_enforce: bool,
+  /// Also show toasts in `WarnOnly` mode, where they'd otherwise be left to the `limit-exceeded` event.
+  pub notify_in_warn_only: bool,
+  /// How often devices are re-enumerated.
+  pub update_interval_ms: u64,
+  /// How often limits are enforced.
+  pub apply_interval_ms: u64,
+  /// Largest volume change made per enforcement tick, so drivers that pop on abrupt drops are brought
+  /// down gradually. 0 moves the volume to its target at once.
+  pub max_step_per_tick: f32,
+  /// How devices are listed.
+  pub sort_order: SortOrder,
+  /// Device IDs in the order used by `SortOrder::Custom`.
+  pub device_order: Vec<String>,
+  pub log_level: LogLevel,
+  /// How the UI maps its volume sliders to scalar volumes.
+  pub volume_curve: VolumeCurve,
+  pub shortcuts: ShortcutConfig,
+  /// COM threading model of the audio thread. Takes effect the next time the audio thread starts.
+  pub com_apartment: ComApartment,
+  /// Version of the app that last wrote the config, for telling which build produced a config file.
+  pub app_version: String
+}
+
+/// Shortest allowed update and apply interval. Polling any faster only burns CPU.
+pub const MIN_INTERVAL_MS: u64 = 20;
+
+/// Whether exceeding a limit brings the volume down or only reports it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitMode {
+  #[default]
+  Enforce,
+  /// Report devices going over their limit without changing their volume, so a user can see what the
+  /// app would do before trusting it with their devices.
+  WarnOnly
+}
+
+/// How `get_devices` orders devices. Ties are always broken by name, then ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+  #[default]
+  NameAsc,
+  NameDesc,
+  /// The default devices for `default_role` first, then the rest by name.
+  DefaultFirst,
+  /// The order in `device_order`, e.g. from reordering devices in the UI.
+  Custom
+}
+
+/// What enforcement does to a device that goes over its cap. Locked volumes are always restored by
+/// changing the volume.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExceedPolicy {
+  /// Bring the volume down to the cap.
+  #[default]
+  Lower,
+  /// Mute the device and leave its volume alone, unmuting it once the volume is brought back under the cap.
+  Mute
+}
+
+/// COM threading model the audio thread initializes with. See the `thread` module for the tradeoffs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ComApartment {
+  /// `COINIT_APARTMENTTHREADED`
+  #[default]
+  Sta,
+  /// `COINIT_MULTITHREADED`
+  Mta
+}
+
+/// How an imported config is combined with the current one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+  /// Use the imported config as is.
+  Replace,
+  /// Only add settings for devices and form factors that don't have one yet.
+  MergeKeepExisting,
+  /// Add the imported settings, overwriting existing ones for the same device or form factor.
+  MergePreferImported
+}
+
+/// What closing the main window does. Quitting from the tray always exits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CloseBehavior {
+  /// Hide the window and keep enforcing limits from the tray.
+  #[default]
+  Tray,
+  Exit
+}
+
+impl Default for AudioDeviceConfig {
+  fn default() -> Self {
+    AudioDeviceConfig {
+      render: VolumeLimits::default(),
+      capture: VolumeLimits::default(),
+      profiles: Vec::new(),
+      active_profile: None,
+      manual_profile: None,
+      device_aliases: HashMap::new(),
+      form_factor_max_volumes: HashMap::new(),
+      new_headphone_default_max: None,
+      default_role: DeviceRole::Multimedia,
+      comms_max_volume: None,
+      duck_priority_process: None,
+      duck_amount: 0.5,
+      seen_device_ids: HashSet::new(),
+      limit_disabled_device_ids: HashSet::new(),
+      ignore_global_device_ids: HashSet::new(),
+      managed_devices: None,
+      device_locked_volumes: HashMap::new(),
+      device_exceed_policies: HashMap::new(),
+      device_poll_intervals_ms: HashMap::new(),
+      enforce_only_when_playing: false,
+      apply_all_on_startup: true,
+      limiting_enabled: true,
+      start_minimized: false,
+      first_run: true,
+      close_behavior: CloseBehavior::Tray,
+      include_disabled_devices: false,
+      global_presets: vec![25, 50, 75, 100],
+      mode: LimitMode::Enforce,
+      notify_on_enforce: false,
+      notify_in_warn_only: false,
+      update_interval_ms: 500,
+      apply_interval_ms: 50,
+      max_step_per_tick: 0.0,
+      sort_order: SortOrder::NameAsc,
+      device_order: Vec::new(),
+      log_level: LogLevel::Info,
+      volume_curve: VolumeCurve::Linear,
+      shortcuts: ShortcutConfig::default(),
+      com_apartment: ComApartment::Sta,
+      app_version: String::new()
+    }
+  }
+}
+
+impl AudioDeviceConfig {
+  /// Parses a config file. Files written before limits were split by data flow have the global and
+  /// per-device limits at the top level; those are moved into `render`, as only output devices existed then.
+  pub fn from_json(json: &str) -> Result<Self, String> {
+    let mut value: serde_json::Value = serde_json::from_str(json).map_err(|err| format!("{err}"))?;
+    if let Some(fields) = value.as_object_mut() {
+      if !fields.contains_key("render") {
+        let mut render = serde_json::Map::new();
+        for field in ["global_max_volume", "device_max_volumes"] {
+          if let Some(value) = fields.remove(field) {
+            render.insert(field.to_string(), value);
+          }
+        }
+        fields.insert("render".to_string(), serde_json::Value::Object(render));
+      }
+    }
+    serde_json::from_value(value).map_err(|err| format!("{err}"))
+  }
+
+  pub fn limits(&self, data_flow: DataFlow) -> &VolumeLimits {
+    match data_flow {
+      DataFlow::Render => &self.render,
+      DataFlow::Capture => &self.capture
+    }
+  }
+
+  pub fn limits_mut(&mut self, data_flow: DataFlow) -> &mut VolumeLimits {
+    match data_flow {
+      DataFlow::Render => &mut self.render,
+      DataFlow::Capture => &mut self.capture
+    }
+  }
+
+  /// Applies an imported config according to `strategy`. The merge strategies only bring in per-device
+  /// and per-form-factor settings and keep everything else, including the global limits. Which devices
+  /// have been seen is always kept, as it describes this machine.
+  pub fn import(&mut self, imported: AudioDeviceConfig, strategy: MergeStrategy) {
+    let prefer_imported = match strategy {
+      MergeStrategy::Replace => {
+        *self = AudioDeviceConfig { seen_device_ids: std::mem::take(&mut self.seen_device_ids), ..imported };
+        return;
+      },
+      MergeStrategy::MergeKeepExisting => false,
+      MergeStrategy::MergePreferImported => true
+    };
+
+    merge_map(&mut self.render.device_max_volumes, imported.render.device_max_volumes, prefer_imported);
+    merge_map(&mut self.capture.device_max_volumes, imported.capture.device_max_volumes, prefer_imported);
+    merge_map(&mut self.device_aliases, imported.device_aliases, prefer_imported);
+    merge_map(&mut self.form_factor_max_volumes, imported.form_factor_max_volumes, prefer_imported);
+    merge_map(&mut self.device_locked_volumes, imported.device_locked_volumes, prefer_imported);
+    merge_map(&mut self.device_exceed_policies, imported.device_exceed_policies, prefer_imported);
+    merge_map(&mut self.device_poll_intervals_ms, imported.device_poll_intervals_ms, prefer_imported);
+    self.limit_disabled_device_ids.extend(imported.limit_disabled_device_ids);
+    self.ignore_global_device_ids.extend(imported.ignore_global_device_ids);
+  }
+
+  /// Checks that every volume is valid without changing anything.
+  pub fn validate(&self) -> Result<(), String> {
+    let fixes = self.clone().validate_and_clamp();
+    match fixes.is_empty() {
+      true => Ok(()),
+      false => Err(fixes.join("; "))
+    }
+  }
+
+  /// Clamps every volume to [0.0, 1.0] and drops non-finite ones, so a hand-edited config can never
+  /// hand an invalid scalar to a device. Returns a description of each fix made.
+  pub fn validate_and_clamp(&mut self) -> Vec<String> {
+    let mut fixes = Vec::new();
+
+    let profile_limits = self.profiles.iter_mut().flat_map(|profile| [&mut profile.render, &mut profile.capture]);
+    for limits in [&mut self.render, &mut self.capture].into_iter().chain(profile_limits) {
+      if !limits.global_max_volume.is_finite() {
+        fixes.push(format!("Global max volume {} is not finite, reset to 1.0", limits.global_max_volume));
+        limits.global_max_volume = 1.0;
+      } else if !(0.0..=1.0).contains(&limits.global_max_volume) {
+        fixes.push(format!("Global max volume {} is out of range, clamped", limits.global_max_volume));
+        limits.global_max_volume = limits.global_max_volume.clamp(0.0, 1.0);
+      }
+      clamp_volumes(&mut limits.device_max_volumes, &mut fixes);
+    }
+
+    if let Some(volume) = self.new_headphone_default_max {
+      if !volume.is_finite() {
+        fixes.push(format!("New headphone default max volume {volume} is not finite, removed"));
+        self.new_headphone_default_max = None;
+      } else if !(0.0..=1.0).contains(&volume) {
+        fixes.push(format!("New headphone default max volume {volume} is out of range, clamped"));
+        self.new_headphone_default_max = Some(volume.clamp(0.0, 1.0));
+      }
+    }
+
+    if let Some(volume) = self.comms_max_volume {
+      if !volume.is_finite() {
+        fixes.push(format!("Communications max volume {volume} is not finite, removed"));
+        self.comms_max_volume = None;
+      } else if !(0.0..=1.0).contains(&volume) {
+        fixes.push(format!("Communications max volume {volume} is out of range, clamped"));
+        self.comms_max_volume = Some(volume.clamp(0.0, 1.0));
+      }
+    }
+
+    if !self.duck_amount.is_finite() {
+      fixes.push(format!("Duck amount {} is not finite, reset to 0.5", self.duck_amount));
+      self.duck_amount = 0.5;
+    } else if !(0.0..=1.0).contains(&self.duck_amount) {
+      fixes.push(format!("Duck amount {} is out of range, clamped", self.duck_amount));
+      self.duck_amount = self.duck_amount.clamp(0.0, 1.0);
+    }
+
+    clamp_volumes(&mut self.form_factor_max_volumes, &mut fixes);
+    clamp_volumes(&mut self.device_locked_volumes, &mut fixes);
+
+    if !self.max_step_per_tick.is_finite() {
+      fixes.push(format!("Max step per tick {} is not finite, reset to 0", self.max_step_per_tick));
+      self.max_step_per_tick = 0.0;
+    } else if !(0.0..=1.0).contains(&self.max_step_per_tick) {
+      fixes.push(format!("Max step per tick {} is out of range, clamped", self.max_step_per_tick));
+      self.max_step_per_tick = self.max_step_per_tick.clamp(0.0, 1.0);
+    }
+
+    let step = self.shortcuts.step;
+    if !step.is_finite() || step <= 0.0 {
+      fixes.push(format!("Shortcut step {step} is invalid, reset to default"));
+      self.shortcuts.step = ShortcutConfig::default().step;
+    } else if step > 1.0 {
+      fixes.push(format!("Shortcut step {step} is out of range, clamped"));
+      self.shortcuts.step = 1.0;
+    }
+
+    for (name, interval_ms) in [("Update", &mut self.update_interval_ms), ("Apply", &mut self.apply_interval_ms)] {
+      if *interval_ms < MIN_INTERVAL_MS {
+        fixes.push(format!("{name} interval {interval_ms}ms is below the minimum, raised to {MIN_INTERVAL_MS}ms"));
+        *interval_ms = MIN_INTERVAL_MS;
+      }
+    }
+    for (device_id, interval_ms) in &mut self.device_poll_intervals_ms {
+      if *interval_ms < MIN_INTERVAL_MS {
+        fixes.push(format!(
+          "Poll interval {interval_ms}ms of device {device_id} is below the minimum, raised to {MIN_INTERVAL_MS}ms"
+        ));
+        *interval_ms = MIN_INTERVAL_MS;
+      }
+    }
+
+    self.global_presets.retain(|percent| {
+      let valid = *percent <= 100;
+      if !valid {
+        fixes.push(format!("Global preset {percent}% is out of range, removed"));
+      }
+      valid
+    });
+
+    fixes
+  }
+}
+
+fn merge_map<K: Eq + std::hash::Hash, V>(existing: &mut HashMap<K, V>, imported: HashMap<K, V>, prefer_imported: bool) {
+  for (key, value) in imported {
+    match prefer_imported {
+      true => {
+        existing.insert(key, value);
+      },
+      false => {
+        existing.entry(key).or_insert(value);
+      }
+    }
+  }
+}
+
+fn clamp_volumes<K: std::fmt::Debug>(volumes: &mut HashMap<K, f32>, fixes: &mut Vec<String>) {
+  volumes.retain(|key, volume| {
+    if !volume.is_finite() {
+      fixes.push(format!("Max volume {volume} for {key:?} is not finite, removed"));
+      return false;
+    }
+    if !(0.0..=1.0).contains(volume) {
+      fixes.push(format!("Max volume {volume} for {key:?} is out of range, clamped"));
+      *volume = volume.clamp(0.0, 1.0);
+    }
+    true
+  });
+}
+
+/// Any subset of the top-level fields of `AudioDeviceConfig`, for updating some fields without
+/// touching the others.
+#[derive(serde::Deserialize)]
+#[serde(transparent)]
+pub struct PartialConfig(serde_json::Map<String, serde_json::Value>);
+
+impl PartialConfig {
+  /// Returns `config` with the provided fields replaced. Fields that aren't part of the config are rejected.
+  pub fn merge_into(self, config: &AudioDeviceConfig) -> Result<AudioDeviceConfig, String> {
+    let mut merged = match serde_json::to_value(config) {
+      Ok(serde_json::Value::Object(fields)) => fields,
+      Ok(_) => return Err("Config didn't serialize to an object".to_string()),
+      Err(err) => return Err(format!("{err}"))
+    };
+
+    for (field, value) in self.0 {
+      if !merged.contains_key(&field) {
+        return Err(format!("Unknown config field '{field}'"));
+      }
+      merged.insert(field, value);
+    }
+
+    serde_json::from_value(serde_json::Value::Object(merged))
+      .map_err(|err| format!("Invalid config: {err}"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn from_json_clamps_out_of_range_volumes() {
+    let mut config = AudioDeviceConfig::from_json(
+      r#"{ "render": { "global_max_volume": 5.0, "device_max_volumes": { "a": -0.5, "b": 0.3 } }, "duck_amount": 2.0 }"#
+    )
+    .unwrap();
+    let fixes = config.validate_and_clamp();
+
+    assert_eq!(fixes.len(), 3);
+    assert_eq!(config.render.global_max_volume, 1.0);
+    assert_eq!(config.render.device_max_volumes["a"], 0.0);
+    assert_eq!(config.render.device_max_volumes["b"], 0.3);
+    assert_eq!(config.duck_amount, 1.0);
+  }
+
+  #[test]
+  fn from_json_moves_legacy_limits_into_render() {
+    let config =
+      AudioDeviceConfig::from_json(r#"{ "global_max_volume": 0.4, "device_max_volumes": { "a": 0.2 } }"#).unwrap();
+
+    assert_eq!(config.render.global_max_volume, 0.4);
+    assert_eq!(config.render.device_max_volumes["a"], 0.2);
+    assert_eq!(config.capture.global_max_volume, 1.0);
+  }
+
+  #[test]
+  fn from_json_rejects_non_numeric_volumes() {
+    assert!(AudioDeviceConfig::from_json(r#"{ "render": { "global_max_volume": NaN } }"#).is_err());
+    assert!(AudioDeviceConfig::from_json(r#"{ "render": { "global_max_volume": "loud" } }"#).is_err());
+  }
+
+  #[test]
+  fn validate_and_clamp_drops_non_finite_values() {
+    let mut config = AudioDeviceConfig {
+      render: VolumeLimits {
+        global_max_volume: f32::NAN,
+        device_max_volumes: HashMap::from([("speakers".to_string(), 0.5)])
+      },
+      capture: VolumeLimits {
+        global_max_volume: 1.0,
+        device_max_volumes: HashMap::from([("mic".to_string(), f32::INFINITY)])
+      },
+      new_headphone_default_max: Some(f32::NAN),
+      comms_max_volume: Some(f32::NEG_INFINITY),
+      max_step_per_tick: f32::NAN,
+      ..AudioDeviceConfig::default()
+    };
+    let fixes = config.validate_and_clamp();
+
+    assert_eq!(fixes.len(), 5);
+    assert_eq!(config.render.global_max_volume, 1.0);
+    assert!(config.capture.device_max_volumes.is_empty());
+    assert_eq!(config.render.device_max_volumes["speakers"], 0.5);
+    assert_eq!(config.new_headphone_default_max, None);
+    assert_eq!(config.comms_max_volume, None);
+    assert_eq!(config.max_step_per_tick, 0.0);
+  }
+
+  #[test]
+  fn validate_and_clamp_raises_intervals_below_the_minimum() {
+    let mut config = AudioDeviceConfig {
+      update_interval_ms: 0,
+      device_poll_intervals_ms: HashMap::from([("a".to_string(), 1)]),
+      shortcuts: ShortcutConfig { step: -0.1, ..ShortcutConfig::default() },
+      global_presets: vec![50, 150],
+      ..AudioDeviceConfig::default()
+    };
+    let fixes = config.validate_and_clamp();
+
+    assert_eq!(fixes.len(), 4);
+    assert_eq!(config.update_interval_ms, MIN_INTERVAL_MS);
+    assert_eq!(config.device_poll_intervals_ms["a"], MIN_INTERVAL_MS);
+    assert_eq!(config.shortcuts.step, ShortcutConfig::default().step);
+    assert_eq!(config.global_presets, vec![50]);
+  }
+
+  fn import_configs() -> (AudioDeviceConfig, AudioDeviceConfig) {
+    let mut current = AudioDeviceConfig::default();
+    current.render.global_max_volume = 0.8;
+    current.render.device_max_volumes.insert("a".to_string(), 0.5);
+    current.render.device_max_volumes.insert("b".to_string(), 0.6);
+    current.seen_device_ids.insert("a".to_string());
+
+    let mut imported = AudioDeviceConfig::default();
+    imported.render.global_max_volume = 0.3;
+    imported.render.device_max_volumes.insert("b".to_string(), 0.2);
+    imported.render.device_max_volumes.insert("c".to_string(), 0.4);
+    imported.seen_device_ids.insert("c".to_string());
+    (current, imported)
+  }
+
+  #[test]
+  fn import_replace_takes_the_imported_config_but_keeps_seen_devices() {
+    let (mut config, imported) = import_configs();
+    config.import(imported, MergeStrategy::Replace);
+
+    assert_eq!(config.render.global_max_volume, 0.3);
+    assert_eq!(config.render.device_max_volumes, HashMap::from([("b".to_string(), 0.2), ("c".to_string(), 0.4)]));
+    assert_eq!(config.seen_device_ids, HashSet::from(["a".to_string()]));
+  }
+
+  #[test]
+  fn import_merge_prefer_imported_overwrites_shared_devices() {
+    let (mut config, imported) = import_configs();
+    config.import(imported, MergeStrategy::MergePreferImported);
+
+    assert_eq!(config.render.global_max_volume, 0.8);
+    assert_eq!(
+      config.render.device_max_volumes,
+      HashMap::from([("a".to_string(), 0.5), ("b".to_string(), 0.2), ("c".to_string(), 0.4)])
+    );
+    assert_eq!(config.seen_device_ids, HashSet::from(["a".to_string()]));
+  }
+
+  #[test]
+  fn import_merge_keep_existing_only_adds_new_devices() {
+    let (mut config, imported) = import_configs();
+    config.import(imported, MergeStrategy::MergeKeepExisting);
+
+    assert_eq!(config.render.global_max_volume, 0.8);
+    assert_eq!(
+      config.render.device_max_volumes,
+      HashMap::from([("a".to_string(), 0.5), ("b".to_string(), 0.6), ("c".to_string(), 0.4)])
+    );
+    assert_eq!(config.seen_device_ids, HashSet::from(["a".to_string()]));
+  }
+
+  #[test]
+  fn validate_and_clamp_leaves_a_valid_config_alone() {
+    let mut config = AudioDeviceConfig::default();
+    config.render.device_max_volumes.insert("a".to_string(), 0.0);
+    config.capture.device_max_volumes.insert("b".to_string(), 1.0);
+
+    assert!(config.validate_and_clamp().is_empty());
+  }
+
+  #[test]
+  fn validate_and_clamp_covers_profile_limits() {
+    let mut config = AudioDeviceConfig {
+      profiles: vec![Profile {
+        name: "Headset".to_string(),
+        render: VolumeLimits { global_max_volume: 1.5, device_max_volumes: HashMap::new() },
+        capture: VolumeLimits::default(),
+        auto_activate_when_present: vec!["headset".to_string()]
+      }],
+      ..AudioDeviceConfig::default()
+    };
+
+    assert_eq!(config.validate_and_clamp().len(), 1);
+    assert_eq!(config.profiles[0].render.global_max_volume, 1.0);
+  }
+}
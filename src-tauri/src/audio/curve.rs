@@ -0,0 +1,76 @@
+//! Mapping between the scalar volumes that limits are stored as and the positions of a UI slider.
+//!
+//! Scalar volume isn't perceived linearly, so a slider that maps straight to it crams most of the
+//! audible change into a small part of its travel. Only what the slider shows changes; limits are always
+//! stored and enforced as scalars.
+
+/// How slider positions map to scalar volumes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeCurve {
+  /// The slider position is the scalar volume.
+  #[default]
+  Linear,
+  /// The scalar volume is the cube of the slider position, which is close to how loudness is perceived.
+  Perceptual
+}
+
+/// Slider position in [0.0, 1.0] for a scalar volume.
+pub fn scalar_to_display(scalar: f32, curve: VolumeCurve) -> f32 {
+  let scalar = scalar.clamp(0.0, 1.0);
+  match curve {
+    VolumeCurve::Linear => scalar,
+    VolumeCurve::Perceptual => scalar.cbrt()
+  }
+}
+
+/// Scalar volume for a slider position in [0.0, 1.0]. The inverse of `scalar_to_display`.
+pub fn display_to_scalar(display: f32, curve: VolumeCurve) -> f32 {
+  let display = display.clamp(0.0, 1.0);
+  match curve {
+    VolumeCurve::Linear => display,
+    VolumeCurve::Perceptual => display.powi(3)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CURVES: [VolumeCurve; 2] = [VolumeCurve::Linear, VolumeCurve::Perceptual];
+
+  #[test]
+  fn endpoints_map_to_themselves() {
+    for curve in CURVES {
+      for volume in [0.0, 1.0] {
+        assert_eq!(scalar_to_display(volume, curve), volume);
+        assert_eq!(display_to_scalar(volume, curve), volume);
+      }
+    }
+  }
+
+  #[test]
+  fn conversions_round_trip() {
+    for curve in CURVES {
+      for volume in [0.0, 0.125, 0.25, 0.5, 0.75, 1.0] {
+        assert!((display_to_scalar(scalar_to_display(volume, curve), curve) - volume).abs() < 1e-6);
+        assert!((scalar_to_display(display_to_scalar(volume, curve), curve) - volume).abs() < 1e-6);
+      }
+    }
+  }
+
+  #[test]
+  fn perceptual_curve_gives_low_volumes_more_travel() {
+    assert!((scalar_to_display(0.125, VolumeCurve::Perceptual) - 0.5).abs() < 1e-6);
+    assert!((display_to_scalar(0.5, VolumeCurve::Perceptual) - 0.125).abs() < 1e-6);
+    assert_eq!(scalar_to_display(0.5, VolumeCurve::Linear), 0.5);
+  }
+
+  #[test]
+  fn out_of_range_values_are_clamped() {
+    for curve in CURVES {
+      assert_eq!(scalar_to_display(1.5, curve), 1.0);
+      assert_eq!(display_to_scalar(-0.5, curve), 0.0);
+    }
+  }
+}
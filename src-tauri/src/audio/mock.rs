@@ -0,0 +1,222 @@
+//! In-memory stand-ins for the WASAPI types, so the controller can be tested and benchmarked without audio
+//! hardware. They replace WASAPI in test builds.
+//!
+//! Devices live in a thread-local table that tests fill with `add_device` before initializing a controller,
+//! and inspect afterwards. Each test runs on its own thread, so tests don't see each other's devices.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use windows::core::GUID;
+
+use crate::audio::{
+  AudioDevice, AudioDeviceEnumerator, DataFlow, DeviceRole, DeviceState, EndpointInfo, FormFactor, HardwareSupport,
+  SessionInfo, VolumeRange, VolumeStepInfo
+};
+
+#[derive(Clone)]
+pub struct MockDeviceState {
+  pub name: String,
+  pub volume: f32,
+  pub muted: bool,
+  pub state: DeviceState,
+  pub form_factor: FormFactor,
+  pub data_flow: DataFlow,
+  /// Makes every handle opened so far fail, as after an audio service restart. Opening the device again
+  /// gives a working handle.
+  pub invalidated: bool,
+  /// Bumped when an invalidated device is opened again, so handles from before keep failing.
+  epoch: u32,
+  /// How many times the device was opened. Opening is where WASAPI activates the device's volume and meter
+  /// interfaces, so this also counts those activations.
+  pub opens: u32,
+  /// How many times the device's name or form factor was read, which WASAPI reads from the property store.
+  pub property_reads: u32,
+  /// How many times `set_volume` was called on the device.
+  pub set_volume_calls: u32
+}
+
+thread_local! {
+  static DEVICES: RefCell<HashMap<String, MockDeviceState>> = RefCell::new(HashMap::new());
+}
+
+/// Adds an active render device at the given volume.
+pub fn add_device(device_id: &str, name: &str, volume: f32) {
+  DEVICES.with_borrow_mut(|devices| {
+    devices.insert(device_id.to_string(), MockDeviceState {
+      name: name.to_string(),
+      volume,
+      muted: false,
+      state: DeviceState::Active,
+      form_factor: FormFactor::Speakers,
+      data_flow: DataFlow::Render,
+      invalidated: false,
+      epoch: 0,
+      opens: 0,
+      property_reads: 0,
+      set_volume_calls: 0
+    });
+  });
+}
+
+/// Changes a device added with `add_device`.
+pub fn update_device(device_id: &str, update: impl FnOnce(&mut MockDeviceState)) {
+  DEVICES.with_borrow_mut(|devices| update(devices.get_mut(device_id).expect("no such mock device")));
+}
+
+pub fn device(device_id: &str) -> MockDeviceState {
+  DEVICES.with_borrow(|devices| devices.get(device_id).cloned().expect("no such mock device"))
+}
+
+/// Sums one of the call counters over every device.
+pub fn count_calls(counter: impl Fn(&MockDeviceState) -> u32) -> u32 {
+  DEVICES.with_borrow(|devices| devices.values().map(counter).sum())
+}
+
+pub struct MockAudioDevice {
+  id: String,
+  epoch: u32
+}
+
+fn with_device<T>(handle: &MockAudioDevice, read: impl FnOnce(&mut MockDeviceState) -> T) -> Result<T, String> {
+  DEVICES.with_borrow_mut(|devices| match devices.get_mut(&handle.id) {
+    Some(device) if device.invalidated || device.epoch != handle.epoch => Err("Device was invalidated".to_string()),
+    Some(device) => Ok(read(device)),
+    None => Err(format!("Device '{}' was removed", handle.id))
+  })
+}
+
+impl AudioDevice for MockAudioDevice {
+  fn get_id(&self) -> Result<String, String> {
+    Ok(self.id.clone())
+  }
+
+  fn get_name(&self) -> Result<String, String> {
+    with_device(self, |device| {
+      device.property_reads += 1;
+      device.name.clone()
+    })
+  }
+
+  fn get_volume(&self) -> Result<f32, String> {
+    with_device(self, |device| device.volume)
+  }
+
+  fn set_volume(&mut self, volume: f32) -> Result<(), String> {
+    with_device(self, |device| {
+      device.volume = volume;
+      device.set_volume_calls += 1;
+    })
+  }
+
+  fn get_mute(&self) -> Result<bool, String> {
+    with_device(self, |device| device.muted)
+  }
+
+  fn set_mute(&mut self, muted: bool) -> Result<(), String> {
+    with_device(self, |device| device.muted = muted)
+  }
+
+  fn query_hardware_support(&self) -> Result<HardwareSupport, String> {
+    Ok(HardwareSupport { volume: true, mute: true, meter: true })
+  }
+
+  fn is_invalidated(&self) -> bool {
+    with_device(self, |_| ()).is_err()
+  }
+
+  fn get_peak_value(&self) -> Result<f32, String> {
+    Ok(0.0)
+  }
+
+  fn get_form_factor(&self) -> FormFactor {
+    with_device(self, |device| {
+      device.property_reads += 1;
+      device.form_factor
+    }).unwrap_or(FormFactor::Unknown)
+  }
+
+  fn get_data_flow(&self) -> DataFlow {
+    with_device(self, |device| device.data_flow).unwrap_or_default()
+  }
+
+  fn get_connector(&self) -> Option<String> {
+    None
+  }
+
+  fn get_icon_path(&self) -> Option<String> {
+    None
+  }
+
+  fn get_instance_id(&self) -> Result<String, String> {
+    Ok(self.id.clone())
+  }
+
+  fn get_state(&self) -> Result<DeviceState, String> {
+    with_device(self, |device| device.state)
+  }
+
+  fn get_channel_count(&self) -> Result<u32, String> {
+    Ok(2)
+  }
+
+  fn get_channel_volume(&self, _channel: u32) -> Result<f32, String> {
+    self.get_volume()
+  }
+
+  fn get_volume_range(&self) -> Result<Option<VolumeRange>, String> {
+    Ok(None)
+  }
+
+  fn get_volume_step_info(&self) -> Result<Option<VolumeStepInfo>, String> {
+    Ok(None)
+  }
+
+  fn get_sessions(&self) -> Result<Vec<SessionInfo>, String> {
+    Ok(Vec::new())
+  }
+
+  fn set_session_volume(&mut self, _instance_id: &str, _volume: f32) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+pub struct MockAudioDeviceEnumerator;
+
+impl AudioDeviceEnumerator<MockAudioDevice> for MockAudioDeviceEnumerator {
+  fn init(_event_context: GUID) -> Result<Self, String> {
+    Ok(MockAudioDeviceEnumerator)
+  }
+
+  fn get_device_states(&self, include_disabled: bool) -> Result<Vec<(String, DeviceState)>, String> {
+    Ok(DEVICES.with_borrow(|devices| {
+      devices.iter()
+        .filter(|(_, device)| match device.state {
+          DeviceState::Active => true,
+          DeviceState::Disabled => include_disabled,
+          _ => false
+        })
+        .map(|(id, device)| (id.clone(), device.state))
+        .collect()
+    }))
+  }
+
+  fn get_device(&self, device_id: &str) -> Result<MockAudioDevice, String> {
+    DEVICES.with_borrow_mut(|devices| {
+      let device = devices.get_mut(device_id).ok_or_else(|| format!("Device '{device_id}' was removed"))?;
+      if device.invalidated {
+        device.invalidated = false;
+        device.epoch += 1;
+      }
+      device.opens += 1;
+      Ok(MockAudioDevice { id: device_id.to_string(), epoch: device.epoch })
+    })
+  }
+
+  fn get_default_device_id(&self, _data_flow: DataFlow, _role: DeviceRole) -> Result<Option<String>, String> {
+    Ok(None)
+  }
+
+  fn get_endpoints(&self, _include_inactive: bool) -> Result<Vec<EndpointInfo>, String> {
+    Ok(Vec::new())
+  }
+}
@@ -1,89 +1,852 @@
-use std::collections::HashMap;
+//! Audio device access and volume limiting.
+//!
+//! `AudioDevice` and `AudioDeviceEnumerator` are the platform-facing traits; `wasapi` is their only real
+//! implementation, and `mock` stands in for it in tests. Device IDs are the endpoint ID strings from
+//! `IMMDevice::GetId`, which are stable across reconnects and are the keys used in the persisted config. Active render and capture endpoints
+//! are enumerated, plus disabled ones when `include_disabled_devices` is set. Each is limited by the
+//! limits of its own data flow.
+//!
+//! `AudioController` holds the config and a cache of enumerated devices and is the only thing the rest
+//! of the app talks to. It is owned by the `AudioThread`, since COM objects must stay on the thread that
+//! created them. New platform code should go behind the traits rather than alongside the controller.
 
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use windows::core::GUID;
+
+use crate::logging::{log, log_enabled, set_log_level, LogLevel};
+
+mod config;
+mod curve;
+#[cfg(test)]
+mod bench;
+#[cfg(test)]
+mod mock;
+mod thread;
+#[cfg_attr(test, allow(dead_code))]
 mod wasapi;
 
+pub use config::{
+  AudioDeviceConfig, CloseBehavior, ComApartment, ExceedPolicy, LimitMode, MergeStrategy, PartialConfig, Profile,
+  ShortcutConfig, SortOrder, MIN_INTERVAL_MS
+};
+pub use curve::{display_to_scalar, scalar_to_display, VolumeCurve};
+pub use thread::AudioThread;
+
+#[cfg(not(test))]
 type AudioDeviceEnumeratorImpl = crate::audio::wasapi::WasapiAudioDeviceEnumerator;
+#[cfg(test)]
+type AudioDeviceEnumeratorImpl = crate::audio::mock::MockAudioDeviceEnumerator;
 
 pub trait AudioDevice {
   fn get_id(&self) -> Result<String, String>;
   fn get_name(&self) -> Result<String, String>;
   fn get_volume(&self) -> Result<f32, String>;
   fn set_volume(&mut self, volume: f32) -> Result<(), String>;
+  fn get_mute(&self) -> Result<bool, String>;
+  fn set_mute(&mut self, muted: bool) -> Result<(), String>;
+  fn query_hardware_support(&self) -> Result<HardwareSupport, String>;
+  /// Whether the interfaces held for the device have gone invalid, e.g. because the Windows Audio service
+  /// restarted, so that only reopening the device would make it usable again.
+  fn is_invalidated(&self) -> bool;
+  fn get_peak_value(&self) -> Result<f32, String>;
+  fn get_form_factor(&self) -> FormFactor;
+  fn get_data_flow(&self) -> DataFlow;
+  /// The kind of jack or connector the endpoint is on, or `None` if the driver doesn't report one.
+  fn get_connector(&self) -> Option<String>;
+  /// Where Windows loads the endpoint's icon from, e.g. `%SystemRoot%\System32\mmres.dll,-3010`, or `None`
+  /// if it has none.
+  fn get_icon_path(&self) -> Option<String>;
+  fn get_instance_id(&self) -> Result<String, String>;
+  fn get_state(&self) -> Result<DeviceState, String>;
+  fn get_channel_count(&self) -> Result<u32, String>;
+  fn get_channel_volume(&self, channel: u32) -> Result<f32, String>;
+  /// The device's volume range in decibels, or `None` if it doesn't report one.
+  fn get_volume_range(&self) -> Result<Option<VolumeRange>, String>;
+  /// The device's volume steps, or `None` if it doesn't have discrete steps.
+  fn get_volume_step_info(&self) -> Result<Option<VolumeStepInfo>, String>;
+  /// The device's audio sessions, skipping expired ones. Only available on active devices.
+  fn get_sessions(&self) -> Result<Vec<SessionInfo>, String>;
+  /// Sets the volume of the session with the given instance ID, as reported in `SessionInfo`.
+  fn set_session_volume(&mut self, instance_id: &str, volume: f32) -> Result<(), String>;
 }
 
 pub trait AudioDeviceEnumerator<T: AudioDevice> {
-  fn init() -> Result<Self, String> where Self: Sized;
-  fn into_iter(&self) -> impl Iterator<Item = T>;
+  /// `event_context` is passed along with every volume and mute change made through the enumerated
+  /// devices, so endpoint notifications caused by our own corrections can be told apart from user changes.
+  fn init(event_context: GUID) -> Result<Self, String> where Self: Sized;
+  /// IDs and states of the active devices, plus disabled ones if `include_disabled` is set. Much cheaper
+  /// than `get_device`, as nothing is activated on the devices.
+  fn get_device_states(&self, include_disabled: bool) -> Result<Vec<(String, DeviceState)>, String>;
+  fn get_device(&self, device_id: &str) -> Result<T, String>;
+  /// ID of the default device for `data_flow` in `role`, or `None` if there are no such devices.
+  fn get_default_device_id(&self, data_flow: DataFlow, role: DeviceRole) -> Result<Option<String>, String>;
+  /// Every render and capture endpoint, or only the active ones, ignoring `include_disabled_devices`. The
+  /// default and `listed` flags are left unset for the controller to fill in.
+  fn get_endpoints(&self, include_inactive: bool) -> Result<Vec<EndpointInfo>, String>;
 }
 
-#[derive(serde::Serialize)]
+/// Which volume controls the device implements in hardware. Anything not supported in hardware is
+/// emulated in software by the audio engine.
+#[derive(Clone, Copy)]
+pub struct HardwareSupport {
+  pub volume: bool,
+  pub mute: bool,
+  pub meter: bool
+}
+
+#[derive(Clone, Copy, serde::Serialize)]
+pub struct VolumeRange {
+  pub min_db: f32,
+  pub max_db: f32,
+  pub increment_db: f32
+}
+
+#[derive(Clone, Copy, serde::Serialize)]
+pub struct VolumeStepInfo {
+  pub step: u32,
+  pub step_count: u32
+}
+
+/// Whether an endpoint plays (render) or records (capture) audio. Limits are kept separately for each.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFlow {
+  #[default]
+  Render,
+  Capture
+}
+
+/// Windows keeps a separate default device for each role: the multimedia one for most apps, and the
+/// communications one for calls. The console role is for system sounds and games, though Windows keeps it
+/// in step with the multimedia default on most setups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceRole {
+  Console,
+  Multimedia,
+  Communications
+}
+
+/// The physical kind of an endpoint, as reported by `PKEY_AudioEndpoint_FormFactor`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum FormFactor {
+  RemoteNetworkDevice,
+  Speakers,
+  LineLevel,
+  Headphones,
+  Microphone,
+  Headset,
+  Handset,
+  UnknownDigitalPassthrough,
+  #[serde(rename = "SPDIF")]
+  Spdif,
+  DigitalAudioDisplayDevice,
+  Unknown
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum DeviceState {
+  Active,
+  Disabled,
+  NotPresent,
+  Unplugged
+}
+
+#[derive(Clone, serde::Serialize)]
 pub struct AudioDeviceInfo {
   pub id: String,
+  /// The user-defined alias if one is set, otherwise the Windows friendly name.
   pub name: String,
-  pub max_volume: f32
+  /// `name`, with a suffix if another device has the same name.
+  pub display_name: String,
+  pub friendly_name: String,
+  /// Whether `friendly_name` could be read. If not, it's the name last read from the device, or a
+  /// placeholder made from the ID if there's none.
+  pub name_available: bool,
+  pub max_volume: f32,
+  pub max_volume_percent: u8,
+  /// The cap enforcement actually applies, after the global, form factor and communications limits. See
+  /// `AudioController::effective_max_volume`.
+  pub effective_max_volume: f32,
+  pub limit_enabled: bool,
+  /// Whether the device ignores the global limit and only obeys its own cap.
+  pub ignore_global: bool,
+  /// Whether the app enforces limits on the device at all. See `AudioDeviceConfig::managed_devices`.
+  pub managed: bool,
+  pub locked_volume: Option<f32>,
+  pub is_muted: bool,
+  pub hardware_volume: bool,
+  pub hardware_mute: bool,
+  pub hardware_meter: bool,
+  pub form_factor: FormFactor,
+  pub data_flow: DataFlow,
+  /// Tells apart endpoints on the same codec that share a friendly name, e.g. "Speaker" and "Headphones".
+  pub connector: Option<String>,
+  /// File the icon Windows shows for the device is in, usually a DLL, with environment variables such as
+  /// `%SystemRoot%` left for the frontend to expand.
+  pub icon_path: Option<String>,
+  /// Which icon in `icon_path`: an index, or a resource ID if negative. `None` if the path doesn't give one,
+  /// e.g. because it names an `.ico` file.
+  pub icon_index: Option<i32>,
+  pub state: DeviceState,
+  pub volume_range: Option<VolumeRange>,
+  pub volume_step_count: Option<u32>,
+  /// The number of channels, or 0 if the device isn't active.
+  pub channel_count: u32,
+  /// The level of each channel. Only the master volume if the channels couldn't be read.
+  pub channel_volumes: Vec<f32>,
+  /// Whether this is the default device for its data flow in the configured `default_role`.
+  pub is_default: bool,
+  /// Whether this is the default communications device for its data flow.
+  pub is_default_comms: bool
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct AudioDeviceConfig {
-  pub global_max_volume: f32,
-  pub device_max_volumes: HashMap<String, f32>
+/// Everything we can read about a device, for bug reports. Each property is either its value or an
+/// `{ "error": ... }` object, so one failing read doesn't hide the rest.
+#[derive(serde::Serialize)]
+pub struct DeviceDiagnostics {
+  pub id: serde_json::Value,
+  pub friendly_name: serde_json::Value,
+  pub instance_id: serde_json::Value,
+  pub state: serde_json::Value,
+  pub form_factor: FormFactor,
+  pub connector: Option<String>,
+  pub volume: serde_json::Value,
+  pub is_muted: serde_json::Value,
+  pub channel_count: serde_json::Value,
+  pub hardware_volume: serde_json::Value,
+  pub hardware_mute: serde_json::Value,
+  pub hardware_meter: serde_json::Value,
+  pub volume_range: serde_json::Value,
+  pub volume_step_info: serde_json::Value
 }
 
-impl Default for AudioDeviceConfig {
-  fn default() -> Self {
-    AudioDeviceConfig {
-      global_max_volume: 1.0,
-      device_max_volumes: HashMap::new()
-    }
+fn diagnostic_value<T: serde::Serialize>(value: Result<T, String>) -> serde_json::Value {
+  match value {
+    Ok(value) => serde_json::to_value(value).unwrap_or(serde_json::Value::Null),
+    Err(err) => serde_json::json!({ "error": err })
   }
 }
 
+/// Outcome of applying a limit to a single device.
+#[derive(serde::Serialize)]
+pub struct DeviceApplyResult {
+  pub device_id: String,
+  pub error: Option<String>
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnforcementAction {
+  /// The volume was brought down to the limit.
+  Corrected,
+  /// The device was muted because its exceed policy is `Mute`. Its volume was left alone.
+  Muted,
+  /// The volume was left alone because the mode is `WarnOnly`.
+  Warned
+}
+
+/// Any endpoint Windows knows about, whether or not the app lists it, so the UI can explain why a device
+/// isn't in `get_devices`.
+#[derive(Clone, serde::Serialize)]
+pub struct EndpointInfo {
+  pub id: String,
+  /// `None` if the name can't be read, which is common for endpoints that aren't present.
+  pub name: Option<String>,
+  pub data_flow: DataFlow,
+  pub state: DeviceState,
+  pub is_default: bool,
+  pub is_default_comms: bool,
+  /// Whether the endpoint is in `get_devices`.
+  pub listed: bool
+}
+
+/// An app's audio session on a device, as shown in the Windows volume mixer.
+#[derive(Clone, serde::Serialize)]
+pub struct SessionInfo {
+  /// Identifies the session for as long as it exists.
+  pub instance_id: String,
+  /// `None` for the system sounds session, and for sessions shared by several processes.
+  pub process_id: Option<u32>,
+  /// File name of the process's executable, or `None` if the process can't be opened, e.g. because it
+  /// runs elevated.
+  pub process_name: Option<String>,
+  /// The name the app gave the session, falling back to the process name.
+  pub display_name: String,
+  pub volume: f32,
+  pub is_muted: bool,
+  pub is_system_sounds: bool,
+  /// Whether the session is playing right now.
+  pub active: bool,
+  pub peak: f32
+}
+
+/// A session lowered by ducking, and the volume to restore it to.
+struct DuckedSession {
+  device_id: String,
+  volume: f32
+}
+
+/// A temporary cap set with `override_device_max_volume`.
+struct CapOverride {
+  max_volume: f32,
+  expires_at: Instant
+}
+
+/// A device whose volume changed, whether from the app, Windows or another app.
+#[derive(Clone, serde::Serialize)]
+pub struct VolumeChange {
+  pub device_id: String,
+  pub volume: f32
+}
+
+/// A device found off its limit by `apply_max_volume`: the device was at `volume`, and `action` tells
+/// whether it was moved to `max_volume`, which is its cap or its locked volume.
+#[derive(Clone, serde::Serialize)]
+pub struct Enforcement {
+  pub device_id: String,
+  pub device_name: String,
+  pub volume: f32,
+  pub max_volume: f32,
+  pub action: EnforcementAction
+}
+
+/// What `apply_max_volume` would do to an active device right now, from `simulate_apply`.
+#[derive(Clone, serde::Serialize)]
+pub struct SimResult {
+  pub device_id: String,
+  pub device_name: String,
+  pub volume: f32,
+  /// The cap enforcement would hold the device to, or its locked volume if it has one.
+  pub max_volume: f32,
+  /// What enforcement would do, or `None` if the device is within its limit or not enforced. `WarnOnly`
+  /// mode reports `Warned` on every call, not just the first.
+  pub action: Option<EnforcementAction>
+}
+
+/// How enforcement sees a device it's responsible for, from `check_limit`.
+struct LimitCheck {
+  volume: f32,
+  /// The effective cap, or the locked volume if the device has one.
+  max_volume: f32,
+  locked: bool,
+  /// The volume the device has to be brought to, or `None` if it's within its limit.
+  target_volume: Option<f32>
+}
+
+/// Device IDs that appeared, disappeared, or stayed the same in an `update_devices` call.
+#[derive(Clone, Default, serde::Serialize)]
+pub struct DeviceChanges {
+  pub added: Vec<String>,
+  pub removed: Vec<String>,
+  pub unchanged: Vec<String>,
+  /// Devices in `unchanged` that were listed while inactive, e.g. disabled, and are active now.
+  pub activated: Vec<String>,
+  /// Whether any default device changed.
+  pub default_changed: bool
+}
+
+impl DeviceChanges {
+  pub fn is_changed(&self) -> bool {
+    !self.added.is_empty() || !self.removed.is_empty() || !self.activated.is_empty() || self.default_changed
+  }
+}
+
+/// Sets the display name of each device. Devices that share a name get a suffix: their connector if no
+/// other device with the name has the same one, otherwise the end of their ID. Both are fixed for a
+/// device, so the suffixes don't change between refreshes.
+fn set_display_names(devices: &mut [AudioDeviceInfo]) {
+  let display_names: Vec<_> = devices.iter()
+    .map(|device| {
+      let same_name: Vec<_> = devices.iter().filter(|other| other.name == device.name).collect();
+      if same_name.len() < 2 {
+        return device.name.clone();
+      }
+
+      let unique_connector = device.connector.as_ref()
+        .filter(|connector| same_name.iter().filter(|other| other.connector.as_ref() == Some(*connector)).count() == 1);
+      match unique_connector {
+        Some(connector) => format!("{} ({connector})", device.name),
+        None => format!("{} ({})", device.name, id_suffix(&device.id))
+      }
+    })
+    .collect();
+
+  for (device, display_name) in devices.iter_mut().zip(display_names) {
+    device.display_name = display_name;
+  }
+}
+
+/// Splits an icon location like `mmres.dll,-3010` into the file and the icon index.
+fn split_icon_path(icon_path: &str) -> (String, Option<i32>) {
+  match icon_path.rsplit_once(',') {
+    Some((path, index)) => match index.trim().parse() {
+      Ok(index) => (path.trim().to_string(), Some(index)),
+      Err(_) => (icon_path.to_string(), None)
+    },
+    None => (icon_path.to_string(), None)
+  }
+}
+
+/// The last four hex digits of a device ID, which tell apart devices that otherwise look the same.
+fn id_suffix(device_id: &str) -> String {
+  let hex_digits: Vec<_> = device_id.chars().filter(char::is_ascii_hexdigit).collect();
+  let id_suffix: String = hex_digits[hex_digits.len().saturating_sub(4)..].iter().collect();
+  id_suffix.to_uppercase()
+}
+
+/// Reads the level of each of the device's channels. Mono devices report a single channel.
+fn read_channel_volumes(device: &dyn AudioDevice) -> Result<Vec<f32>, String> {
+  let channel_count = device.get_channel_count()?;
+  if channel_count == 0 {
+    return Err("Device reported no channels".to_string());
+  }
+  (0..channel_count).map(|channel| device.get_channel_volume(channel)).collect()
+}
+
+/// Checks that a volume from outside the app is a scalar in [0.0, 1.0]. NaN has to be ruled out
+/// explicitly, as it fails every comparison and would otherwise slip through to the driver.
+pub fn validate_volume(volume: f32) -> Result<(), String> {
+  if !volume.is_finite() {
+    return Err(format!("Volume must be a finite number, got {volume}"));
+  }
+  if !(0.0..=1.0).contains(&volume) {
+    return Err("Max volume must be between 0.0 and 1.0".to_string());
+  }
+  Ok(())
+}
+
+/// Converts a 0–100 percentage to a volume scalar.
+pub fn percent_to_volume(percent: u8) -> Result<f32, String> {
+  if percent > 100 {
+    return Err("Max volume percentage must be between 0 and 100".to_string());
+  }
+  Ok(percent as f32 / 100.0)
+}
+
+/// Converts a volume scalar to the nearest whole percentage.
+pub fn volume_to_percent(volume: f32) -> u8 {
+  (volume.clamp(0.0, 1.0) * 100.0).round() as u8
+}
+
+/// Volumes within this distance of the cap count as being at the cap. Drivers report levels with small
+/// float differences from what was set, and correcting those would re-set the volume on every tick.
+const VOLUME_EPSILON: f32 = 0.005;
+
+/// Minimum time between enumerator recreation attempts, doubled with every consecutive failure.
+const ENUMERATOR_RECOVERY_BASE_COOLDOWN: Duration = Duration::from_secs(1);
+/// How long devices have to stay valid after a rebuild before the rebuild backoff starts over.
+const REBUILD_BACKOFF_RESET: Duration = Duration::from_secs(300);
+
+/// Peak meter values at or below this are treated as silence.
+const PLAYING_PEAK_THRESHOLD: f32 = 0.0001;
+
 pub struct AudioController {
   device_enumerator: AudioDeviceEnumeratorImpl,
+  /// Replaced wholesale by `update_devices` and `reinitialize`. A device reference must never outlive a
+  /// swap: methods look devices up by ID on every call and never hand references out, and since every
+  /// caller runs as a job on the audio thread, an apply and an update can't interleave.
   device_cache: HashMap<String, Box<dyn AudioDevice>>,
-  global_max_volume: f32,
-  device_max_volumes: HashMap<String, f32>
+  /// The state each cached device was opened in. Devices are reused across updates until their state
+  /// changes, since their volume interfaces can only be activated while they're active.
+  device_states: HashMap<String, DeviceState>,
+  config: AudioDeviceConfig,
+  event_context: GUID,
+  /// Consecutive enumeration failures that the enumerator couldn't be recovered from.
+  enumerator_failures: u32,
+  last_enumerator_recovery: Option<Instant>,
+  enumerator_recovered: bool,
+  /// Rebuilds of invalidated devices made in a row, for backing off. See `take_rebuild_due`.
+  rebuilds: u32,
+  last_rebuild: Option<Instant>,
+  /// Devices currently over their limit that have been warned about in `WarnOnly` mode, so each crossing
+  /// is only reported once.
+  warned_device_ids: HashSet<String>,
+  /// Devices muted because they went over their cap with the `Mute` policy, to be unmuted once they're
+  /// back under it. Devices the user muted themselves are never unmuted.
+  muted_device_ids: HashSet<String>,
+  /// Volume of each active device as of the last `take_volume_changes`.
+  last_volumes: HashMap<String, f32>,
+  /// Default device IDs as of the last device update.
+  default_device_ids: HashMap<(DataFlow, DeviceRole), String>,
+  /// Sessions currently lowered by ducking, by instance ID.
+  ducked_sessions: HashMap<String, DuckedSession>,
+  /// When limits were last enforced on each device with its own poll interval.
+  last_polled: HashMap<String, Instant>,
+  /// Friendly name of each device as of when it was last opened, for when reading it fails later.
+  device_names: HashMap<String, String>,
+  /// Temporary caps by device ID. Kept in memory only, so a restart reverts them.
+  cap_overrides: HashMap<String, CapOverride>,
+  /// The profile whose devices were all present as of the last `auto_activate_profile`.
+  auto_profile: Option<String>
 }
 
-impl Into<AudioDeviceConfig> for &mut AudioController {
-  fn into(self) -> AudioDeviceConfig {
-    AudioDeviceConfig {
-      global_max_volume: self.global_max_volume,
-      device_max_volumes: self.device_max_volumes.clone()
-    }
+impl From<&mut AudioController> for AudioDeviceConfig {
+  fn from(controller: &mut AudioController) -> Self {
+    controller.config.clone()
   }
 }
 
 impl AudioController {
-  pub fn init(config: AudioDeviceConfig) -> Result<Self, String> {
+  pub fn init(config: AudioDeviceConfig, event_context: GUID) -> Result<Self, String> {
+    set_log_level(config.log_level);
     Ok(AudioController {
-      device_enumerator: AudioDeviceEnumeratorImpl::init()?,
+      device_enumerator: AudioDeviceEnumeratorImpl::init(event_context)?,
       device_cache: HashMap::new(),
-      global_max_volume: config.global_max_volume,
-      device_max_volumes: config.device_max_volumes
+      device_states: HashMap::new(),
+      config,
+      event_context,
+      enumerator_failures: 0,
+      last_enumerator_recovery: None,
+      enumerator_recovered: false,
+      rebuilds: 0,
+      last_rebuild: None,
+      warned_device_ids: HashSet::new(),
+      muted_device_ids: HashSet::new(),
+      last_volumes: HashMap::new(),
+      default_device_ids: HashMap::new(),
+      ducked_sessions: HashMap::new(),
+      last_polled: HashMap::new(),
+      device_names: HashMap::new(),
+      cap_overrides: HashMap::new(),
+      auto_profile: None
     })
   }
 
-  pub fn update_devices(&mut self) -> Result<bool, String> {
-    let new_devices = self.device_enumerator.into_iter()
-      .map(|device| {
-        let id = device.get_id().unwrap_or_default();
-        (id, Box::new(device) as Box<dyn AudioDevice>)
-      })
-      .collect::<HashMap<_, _>>();
-    let changed = new_devices.len() != self.device_cache.len()
-      || new_devices.keys().any(|id| !self.device_cache.contains_key(id));
-    self.device_cache = new_devices;
-    Ok(changed)
+  fn enumerate_devices(&self) -> Result<Vec<(String, DeviceState)>, String> {
+    self.device_enumerator.get_device_states(self.config.include_disabled_devices)
+  }
+
+  /// Replaces the device enumerator with a fresh one, e.g. after the Windows audio service restarted
+  /// and invalidated the old one. Repeated failures back off exponentially so a broken audio stack
+  /// doesn't get hammered with recreation attempts on every poll.
+  fn recover_enumerator(&mut self) -> Result<(), String> {
+    let cooldown = ENUMERATOR_RECOVERY_BASE_COOLDOWN * 2u32.pow(self.enumerator_failures.min(6));
+    if self.last_enumerator_recovery.is_some_and(|last_recovery| last_recovery.elapsed() < cooldown) {
+      return Err("Waiting before recreating the device enumerator again".to_string());
+    }
+
+    self.last_enumerator_recovery = Some(Instant::now());
+    self.device_enumerator = AudioDeviceEnumeratorImpl::init(self.event_context)?;
+    Ok(())
+  }
+
+  /// Whether every cached device has gone invalid, as they all do when the Windows Audio service restarts,
+  /// and it's time to rebuild them with `reinitialize`. Probes every device, so it's only worth calling
+  /// after something failed. Rebuilds back off exponentially while they keep being needed, so a service
+  /// that keeps restarting doesn't cause a rebuild on every tick.
+  pub fn take_rebuild_due(&mut self) -> bool {
+    let invalidated = !self.device_cache.is_empty()
+      && self.device_cache.values().all(|device| device.is_invalidated());
+    if !invalidated {
+      return false;
+    }
+
+    if let Some(last_rebuild) = self.last_rebuild {
+      if last_rebuild.elapsed() > REBUILD_BACKOFF_RESET {
+        self.rebuilds = 0;
+      }
+      let cooldown = ENUMERATOR_RECOVERY_BASE_COOLDOWN * 2u32.pow(self.rebuilds.min(6));
+      if last_rebuild.elapsed() < cooldown {
+        return false;
+      }
+    }
+    self.last_rebuild = Some(Instant::now());
+    self.rebuilds += 1;
+    log(LogLevel::Warn, "Audio devices were invalidated, likely by the audio service restarting. Rebuilding them");
+    true
+  }
+
+  /// Whether the enumerator was recreated since the last call.
+  pub fn take_enumerator_recovered(&mut self) -> bool {
+    std::mem::take(&mut self.enumerator_recovered)
+  }
+
+  pub fn update_devices(&mut self) -> Result<DeviceChanges, String> {
+    let mut reuse = true;
+    let new_devices = match self.enumerate_devices() {
+      Ok(devices) => devices,
+      Err(err) => {
+        let recovered = self.recover_enumerator().and_then(|_| self.enumerate_devices());
+        match recovered {
+          Ok(devices) => {
+            // Devices opened through the old enumerator may have been invalidated along with it.
+            self.enumerator_recovered = true;
+            reuse = false;
+            devices
+          },
+          Err(_) => {
+            self.enumerator_failures += 1;
+            return Err(err);
+          }
+        }
+      }
+    };
+    self.enumerator_failures = 0;
+    Ok(self.replace_devices(new_devices, reuse))
+  }
+
+  /// Drops every cached device and the enumerator and acquires them again. Used after the system resumes
+  /// from sleep, when Windows has re-initialized the audio devices and the interfaces we hold may be stale.
+  pub fn reinitialize(&mut self) -> Result<DeviceChanges, String> {
+    self.device_enumerator = AudioDeviceEnumeratorImpl::init(self.event_context)?;
+    let new_devices = self.enumerate_devices()?;
+    Ok(self.replace_devices(new_devices, false))
+  }
+
+  /// Rebuilds the cache from the enumerated device states. With `reuse`, devices that are still there in
+  /// the same state are kept as they are, and only the rest are opened.
+  fn replace_devices(&mut self, new_devices: Vec<(String, DeviceState)>, reuse: bool) -> DeviceChanges {
+    let mut changes = DeviceChanges::default();
+    let mut old_cache = std::mem::take(&mut self.device_cache);
+    let old_states = std::mem::take(&mut self.device_states);
+
+    for (id, state) in new_devices {
+      let cached = old_cache.remove(&id).filter(|_| reuse && old_states.get(&id) == Some(&state));
+      let device = match cached {
+        Some(device) => device,
+        None => match self.device_enumerator.get_device(&id) {
+          Ok(device) => {
+            if let Ok(name) = device.get_name() {
+              self.device_names.insert(id.clone(), name);
+            }
+            Box::new(device) as Box<dyn AudioDevice>
+          },
+          Err(err) => {
+            log(LogLevel::Error, format!("Couldn't open device '{id}': {err}"));
+            continue;
+          }
+        }
+      };
+      match old_states.get(&id) {
+        Some(old_state) => {
+          if *old_state != DeviceState::Active && state == DeviceState::Active {
+            changes.activated.push(id.clone());
+          }
+          changes.unchanged.push(id.clone());
+        },
+        None => changes.added.push(id.clone())
+      }
+      self.device_cache.insert(id.clone(), device);
+      self.device_states.insert(id, state);
+    }
+    changes.removed = old_states.into_keys()
+      .filter(|id| !self.device_cache.contains_key(id))
+      .collect();
+    changes.added.sort();
+    changes.removed.sort();
+    changes.unchanged.sort();
+    changes.activated.sort();
+
+    let default_device_ids = self.read_default_device_ids();
+    changes.default_changed = default_device_ids != self.default_device_ids;
+    self.default_device_ids = default_device_ids;
+    changes
+  }
+
+  fn read_default_device_ids(&self) -> HashMap<(DataFlow, DeviceRole), String> {
+    let mut default_device_ids = HashMap::new();
+    for data_flow in [DataFlow::Render, DataFlow::Capture] {
+      for role in [DeviceRole::Console, DeviceRole::Multimedia, DeviceRole::Communications] {
+        match self.device_enumerator.get_default_device_id(data_flow, role) {
+          Ok(Some(id)) => {
+            default_device_ids.insert((data_flow, role), id);
+          },
+          Ok(None) => {},
+          Err(err) => log(LogLevel::Error, err)
+        }
+      }
+    }
+    default_device_ids
+  }
+
+  fn is_default_device(&self, device_id: &str, data_flow: DataFlow, role: DeviceRole) -> bool {
+    self.default_device_ids.get(&(data_flow, role)).is_some_and(|id| id == device_id)
+  }
+
+  /// Marks newly appeared devices as seen, and gives headphones and headsets that have never been seen
+  /// before the `new_headphone_default_max` cap if they have no limit of their own. Devices that were
+  /// seen before are left alone, so reconnecting a device never re-clamps it. Returns whether the config
+  /// changed, and the IDs of the devices that were limited.
+  pub fn apply_new_device_defaults(&mut self, device_ids: &[String]) -> (bool, Vec<String>) {
+    let mut changed = false;
+    let mut limited = Vec::new();
+    for device_id in device_ids {
+      if !self.config.seen_device_ids.insert(device_id.clone()) {
+        continue;
+      }
+      changed = true;
+
+      let Some(default_max_volume) = self.config.new_headphone_default_max else {
+        continue;
+      };
+      // A headset's microphone has the headset form factor too, but the default is an output cap.
+      let is_headphones = self.device_cache.get(device_id).is_some_and(|device| {
+        device.get_data_flow() == DataFlow::Render
+          && matches!(device.get_form_factor(), FormFactor::Headphones | FormFactor::Headset)
+      });
+
+      let limits = self.config.limits_mut(DataFlow::Render);
+      if is_headphones && !limits.device_max_volumes.contains_key(device_id) {
+        limits.device_max_volumes.insert(device_id.clone(), default_max_volume);
+        limited.push(device_id.clone());
+      }
+    }
+    (changed, limited)
   }
 
   fn to_audio_device_info(&self, device: &Box<dyn AudioDevice>) -> Result<AudioDeviceInfo, String> {
     let id = device.get_id()?;
-    Ok(AudioDeviceInfo {
+    let state = device.get_state()?;
+    // A device whose property store is briefly unavailable is still listed and limited, under the name
+    // it had before.
+    let (friendly_name, name_available) = match device.get_name() {
+      Ok(name) => (name, true),
+      Err(err) => {
+        log(LogLevel::Debug, format!("Couldn't read name of {id}: {err}"));
+        let name = self.device_names.get(&id).cloned()
+          .unwrap_or_else(|| format!("Unknown device ({})", id_suffix(&id)));
+        (name, false)
+      }
+    };
+    let data_flow = device.get_data_flow();
+    let max_volume = self.config.limits(data_flow).device_max_volumes.get(&id).cloned().unwrap_or(1.0);
+    let name = self.config.device_aliases.get(&id).cloned().unwrap_or_else(|| friendly_name.clone());
+    let (icon_path, icon_index) = match device.get_icon_path() {
+      Some(icon_path) => {
+        let (path, index) = split_icon_path(&icon_path);
+        (Some(path), index)
+      },
+      None => (None, None)
+    };
+    let mut info = AudioDeviceInfo {
       id: id.clone(),
-      name: device.get_name()?,
-      max_volume: self.device_max_volumes.get(&id).cloned().unwrap_or(1.0)
-    })
+      display_name: name.clone(),
+      name,
+      friendly_name,
+      name_available,
+      max_volume,
+      max_volume_percent: volume_to_percent(max_volume),
+      effective_max_volume: self.effective_max_volume(&id),
+      limit_enabled: self.get_device_limit_enabled(&id),
+      ignore_global: self.config.ignore_global_device_ids.contains(&id),
+      managed: self.is_managed(&id),
+      locked_volume: self.config.device_locked_volumes.get(&id).copied(),
+      is_muted: false,
+      hardware_volume: false,
+      hardware_mute: false,
+      hardware_meter: false,
+      form_factor: device.get_form_factor(),
+      data_flow,
+      connector: device.get_connector(),
+      icon_path,
+      icon_index,
+      state,
+      volume_range: None,
+      volume_step_count: None,
+      channel_count: 0,
+      channel_volumes: Vec::new(),
+      is_default: self.is_default_device(&id, data_flow, self.config.default_role),
+      is_default_comms: self.is_default_device(&id, data_flow, DeviceRole::Communications)
+    };
+
+    // Volume controls can only be read from active devices.
+    if state == DeviceState::Active {
+      let hardware_support = device.query_hardware_support()?;
+      info.is_muted = device.get_mute()?;
+      info.hardware_volume = hardware_support.volume;
+      info.hardware_mute = hardware_support.mute;
+      info.hardware_meter = hardware_support.meter;
+      info.volume_range = device.get_volume_range()?;
+      info.volume_step_count = device.get_volume_step_info()?.map(|step_info| step_info.step_count);
+      info.channel_volumes = match read_channel_volumes(device.as_ref()) {
+        Ok(channel_volumes) => channel_volumes,
+        Err(err) => {
+          log(LogLevel::Debug, format!("Couldn't read channels of {id}: {err}"));
+          vec![device.get_volume()?]
+        }
+      };
+      info.channel_count = info.channel_volumes.len() as u32;
+    }
+    Ok(info)
+  }
+
+  /// Reads every available property of every cached device. Doesn't change any device.
+  pub fn get_device_diagnostics(&self) -> Vec<DeviceDiagnostics> {
+    let mut diagnostics: Vec<_> = self.device_cache.values()
+      .map(|device| {
+        let hardware_support = device.query_hardware_support();
+        DeviceDiagnostics {
+          id: diagnostic_value(device.get_id()),
+          friendly_name: diagnostic_value(device.get_name()),
+          instance_id: diagnostic_value(device.get_instance_id()),
+          state: diagnostic_value(device.get_state()),
+          form_factor: device.get_form_factor(),
+          connector: device.get_connector(),
+          volume: diagnostic_value(device.get_volume()),
+          is_muted: diagnostic_value(device.get_mute()),
+          channel_count: diagnostic_value(device.get_channel_count()),
+          hardware_volume: diagnostic_value(hardware_support.clone().map(|support| support.volume)),
+          hardware_mute: diagnostic_value(hardware_support.clone().map(|support| support.mute)),
+          hardware_meter: diagnostic_value(hardware_support.clone().map(|support| support.meter)),
+          volume_range: diagnostic_value(device.get_volume_range()),
+          volume_step_info: diagnostic_value(device.get_volume_step_info())
+        }
+      })
+      .collect();
+
+    diagnostics.sort_by(|a, b| a.id.to_string().cmp(&b.id.to_string()));
+    diagnostics
+  }
+
+  /// ID of the current default output device for `default_role`, read from the system rather than the last
+  /// device update.
+  pub fn get_default_device_id(&self) -> Result<Option<String>, String> {
+    self.device_enumerator.get_default_device_id(DataFlow::Render, self.config.default_role)
+  }
+
+  /// The current default output device, or `None` if there is none or it hasn't been enumerated yet.
+  pub fn get_default_device(&self) -> Result<Option<AudioDeviceInfo>, String> {
+    let Some(device_id) = self.get_default_device_id()? else {
+      return Ok(None);
+    };
+    match self.device_cache.get(&device_id) {
+      Some(device) => self.to_audio_device_info(device).map(Some),
+      None => Ok(None)
+    }
+  }
+
+  /// Caps the current default output device and returns it. The cap is an ordinary per-device cap, so it
+  /// stays with the device if another one becomes the default later.
+  pub fn limit_default_device(&mut self, max_volume: f32) -> Result<AudioDeviceInfo, String> {
+    validate_volume(max_volume)?;
+    let device_id = self.get_default_device_id()?.ok_or("There is no default output device")?;
+    if !self.device_cache.contains_key(&device_id) {
+      return Err("The default output device hasn't been enumerated yet".to_string());
+    }
+
+    self.set_device_max_volume(&device_id, max_volume)?;
+    let device = self.device_cache.get(&device_id)
+      .ok_or_else(|| format!("Device with ID '{}' not found", device_id))?;
+    self.to_audio_device_info(device)
+  }
+
+  /// Lists endpoints straight from Windows, for diagnosing devices missing from `get_devices`. Doesn't
+  /// change what gets listed or limited.
+  pub fn get_all_endpoints(&self, include_inactive: bool) -> Result<Vec<EndpointInfo>, String> {
+    let default_device_ids = self.read_default_device_ids();
+    let is_default = |id: &str, data_flow: DataFlow, role: DeviceRole| {
+      default_device_ids.get(&(data_flow, role)).is_some_and(|default_id| default_id == id)
+    };
+
+    let mut endpoints = self.device_enumerator.get_endpoints(include_inactive)?;
+    for endpoint in &mut endpoints {
+      endpoint.is_default = is_default(&endpoint.id, endpoint.data_flow, self.config.default_role);
+      endpoint.is_default_comms = is_default(&endpoint.id, endpoint.data_flow, DeviceRole::Communications);
+      endpoint.listed = self.device_cache.contains_key(&endpoint.id);
+    }
+    Ok(endpoints)
   }
 
   pub fn get_devices(&self) -> Vec<AudioDeviceInfo> {
@@ -92,58 +855,1149 @@ impl AudioController {
         match self.to_audio_device_info(device) {
           Ok(info) => Some(info),
           Err(err) => {
-            eprintln!("{err}");
+            log(LogLevel::Error, err);
             None
           }
         }
       })
       .collect::<Vec<_>>();
 
-    devices.sort_by(|a, b| match a.name.cmp(&b.name) {
-      std::cmp::Ordering::Equal => a.id.cmp(&b.id),
-      other => other
-    });
+    let by_name = |a: &AudioDeviceInfo, b: &AudioDeviceInfo| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id));
+    match self.config.sort_order {
+      SortOrder::NameAsc => devices.sort_by(by_name),
+      SortOrder::NameDesc => devices.sort_by(|a, b| by_name(b, a)),
+      SortOrder::DefaultFirst => devices.sort_by(|a, b| b.is_default.cmp(&a.is_default).then_with(|| by_name(a, b))),
+      SortOrder::Custom => {
+        // Devices missing from the order, e.g. ones connected after it was saved, go last by name.
+        let order = &self.config.device_order;
+        let rank = |device: &AudioDeviceInfo| order.iter().position(|id| *id == device.id).unwrap_or(order.len());
+        devices.sort_by(|a, b| rank(a).cmp(&rank(b)).then_with(|| by_name(a, b)));
+      }
+    }
+    set_display_names(&mut devices);
     devices
   }
 
-  pub fn get_global_max_volume(&self) -> f32 {
-    self.global_max_volume
+  pub fn get_config(&self) -> AudioDeviceConfig {
+    self.config.clone()
+  }
+
+  /// Runs `update` and returns its result, along with the config from before it if it changed the config.
+  pub fn change_config<R>(
+    &mut self,
+    update: impl FnOnce(&mut Self) -> Result<R, String>
+  ) -> Result<(R, Option<AudioDeviceConfig>), String> {
+    let previous_config = self.config.clone();
+    let result = update(self)?;
+    let changed = self.config != previous_config;
+    Ok((result, changed.then_some(previous_config)))
+  }
+
+  /// Replaces the whole config and applies the new limits to connected devices. Enforcement errors are
+  /// left to the periodic task to report.
+  pub fn set_config(&mut self, config: AudioDeviceConfig) {
+    set_log_level(config.log_level);
+    self.config = config;
+    for device_id in self.limited_device_ids() {
+      let _ = self.apply_max_volume(&device_id);
+    }
+  }
+
+  pub fn get_global_max_volume(&self, data_flow: DataFlow) -> f32 {
+    self.config.limits(data_flow).global_max_volume
+  }
+
+  /// The data flow of a cached device. Devices that aren't connected are assumed to be outputs.
+  fn device_data_flow(&self, device_id: &str) -> DataFlow {
+    self.device_cache.get(device_id)
+      .map(|device| device.get_data_flow())
+      .unwrap_or_default()
+  }
+
+  pub fn get_limiting_enabled(&self) -> bool {
+    self.config.limiting_enabled
+  }
+
+  /// Turns enforcement on or off. Limits stay configured either way, and are applied right away when
+  /// turned back on. Turning it off unmutes the devices muted under the `Mute` policy.
+  pub fn set_limiting_enabled(&mut self, enabled: bool) {
+    self.config.limiting_enabled = enabled;
+    match enabled {
+      true => {
+        for device_id in self.limited_device_ids() {
+          let _ = self.apply_max_volume(&device_id);
+        }
+      },
+      false => self.release_mutes()
+    }
+  }
+
+  pub fn get_first_run(&self) -> bool {
+    self.config.first_run
+  }
+
+  pub fn acknowledge_first_run(&mut self) {
+    self.config.first_run = false;
+  }
+
+  pub fn set_start_minimized(&mut self, start_minimized: bool) {
+    self.config.start_minimized = start_minimized;
+  }
+
+  pub fn set_close_behavior(&mut self, close_behavior: CloseBehavior) {
+    self.config.close_behavior = close_behavior;
+  }
+
+  pub fn set_com_apartment(&mut self, com_apartment: ComApartment) {
+    self.config.com_apartment = com_apartment;
+  }
+
+  pub fn set_default_role(&mut self, default_role: DeviceRole) {
+    self.config.default_role = default_role;
+  }
+
+  pub fn set_notify_on_enforce(&mut self, notify_on_enforce: bool, notify_in_warn_only: bool) {
+    self.config.notify_on_enforce = notify_on_enforce;
+    self.config.notify_in_warn_only = notify_in_warn_only;
+  }
+
+  /// Switching to `WarnOnly` unmutes the devices muted under the `Mute` policy, since that mode leaves
+  /// devices alone.
+  pub fn set_mode(&mut self, mode: LimitMode) {
+    self.config.mode = mode;
+    self.warned_device_ids.clear();
+    if mode == LimitMode::WarnOnly {
+      self.release_mutes();
+    }
+  }
+
+  pub fn set_intervals(&mut self, update_interval_ms: u64, apply_interval_ms: u64) {
+    self.config.update_interval_ms = update_interval_ms.max(MIN_INTERVAL_MS);
+    self.config.apply_interval_ms = apply_interval_ms.max(MIN_INTERVAL_MS);
+  }
+
+  pub fn set_max_step_per_tick(&mut self, max_step_per_tick: f32) -> Result<(), String> {
+    validate_volume(max_step_per_tick)?;
+    self.config.max_step_per_tick = max_step_per_tick;
+    Ok(())
+  }
+
+  pub fn set_volume_curve(&mut self, volume_curve: VolumeCurve) {
+    self.config.volume_curve = volume_curve;
+  }
+
+  pub fn set_shortcuts(&mut self, shortcuts: ShortcutConfig) -> Result<(), String> {
+    if !shortcuts.step.is_finite() || shortcuts.step <= 0.0 || shortcuts.step > 1.0 {
+      return Err(format!("Shortcut step must be above 0 and at most 1, got {}", shortcuts.step));
+    }
+    self.config.shortcuts = shortcuts;
+    Ok(())
+  }
+
+  pub fn set_log_level(&mut self, log_level: LogLevel) {
+    set_log_level(log_level);
+    self.config.log_level = log_level;
+  }
+
+  pub fn set_sort_order(&mut self, sort_order: SortOrder) {
+    self.config.sort_order = sort_order;
+  }
+
+  /// Stores a user-defined device order and switches to it.
+  pub fn set_device_order(&mut self, device_ids: Vec<String>) {
+    self.config.device_order = device_ids;
+    self.config.sort_order = SortOrder::Custom;
+  }
+
+  /// Saves the current limits as a profile, replacing the profile with the same name in place, and makes it
+  /// the active one.
+  pub fn save_profile(&mut self, name: &str, auto_activate_when_present: Vec<String>) -> Result<(), String> {
+    let name = name.trim();
+    if name.is_empty() {
+      return Err("Profile name can't be empty".to_string());
+    }
+    let profile = Profile {
+      name: name.to_string(),
+      render: self.config.render.clone(),
+      capture: self.config.capture.clone(),
+      auto_activate_when_present
+    };
+    match self.config.profiles.iter_mut().find(|existing| existing.name == name) {
+      Some(existing) => *existing = profile,
+      None => self.config.profiles.push(profile)
+    }
+    self.config.active_profile = Some(name.to_string());
+    Ok(())
+  }
+
+  /// Removes a profile. The current limits are kept.
+  pub fn delete_profile(&mut self, name: &str) {
+    self.config.profiles.retain(|profile| profile.name != name);
+    for profile in [&mut self.config.active_profile, &mut self.config.manual_profile] {
+      if profile.as_deref() == Some(name) {
+        *profile = None;
+      }
+    }
+  }
+
+  /// Reorders the profiles, which sets which one wins when the devices of several are present. Profiles
+  /// missing from `names` keep their order after the listed ones.
+  pub fn set_profile_order(&mut self, names: Vec<String>) {
+    let rank = |profile: &Profile| names.iter().position(|name| *name == profile.name).unwrap_or(names.len());
+    self.config.profiles.sort_by_key(rank);
+  }
+
+  /// Switches to a profile by hand. It's remembered, so it's switched back to once no profile's devices are
+  /// present, and it holds until a device connects or disconnects that changes which profile they match.
+  pub fn activate_profile(&mut self, name: &str) -> Result<(), String> {
+    self.switch_profile(name)?;
+    self.config.manual_profile = Some(name.to_string());
+    Ok(())
+  }
+
+  /// Switches to the first profile whose devices are all present, or back to the profile last activated
+  /// by hand once no profile's are. Only acts when which profile matches changes, so a profile activated by
+  /// hand isn't overridden on every update. Returns the profile switched to.
+  pub fn auto_activate_profile(&mut self) -> Option<String> {
+    let present = |device_id: &String| self.device_states.get(device_id) == Some(&DeviceState::Active);
+    let matched = self.config.profiles.iter()
+      .find(|profile| {
+        !profile.auto_activate_when_present.is_empty() && profile.auto_activate_when_present.iter().all(&present)
+      })
+      .map(|profile| profile.name.clone());
+    if matched == self.auto_profile {
+      return None;
+    }
+    self.auto_profile = matched.clone();
+
+    let name = matched.or_else(|| self.config.manual_profile.clone())?;
+    if self.config.active_profile.as_ref() == Some(&name) {
+      return None;
+    }
+    match self.switch_profile(&name) {
+      Ok(()) => Some(name),
+      Err(err) => {
+        log(LogLevel::Error, format!("Couldn't activate profile: {err}"));
+        None
+      }
+    }
+  }
+
+  /// Copies a profile's limits into the config and applies them to connected devices. Enforcement errors
+  /// are left to the periodic task to report.
+  fn switch_profile(&mut self, name: &str) -> Result<(), String> {
+    let profile = self.config.profiles.iter()
+      .find(|profile| profile.name == name)
+      .ok_or_else(|| format!("Profile '{name}' not found"))?;
+    self.config.render = profile.render.clone();
+    self.config.capture = profile.capture.clone();
+    self.config.active_profile = Some(name.to_string());
+    for device_id in self.limited_device_ids() {
+      let _ = self.apply_max_volume(&device_id);
+    }
+    Ok(())
+  }
+
+  pub fn set_include_disabled_devices(&mut self, include_disabled_devices: bool) {
+    self.config.include_disabled_devices = include_disabled_devices;
   }
 
   pub fn set_device_max_volume(&mut self, device_id: &str, max_volume: f32) -> Result<(), String> {
-    if max_volume < 0.0 || max_volume > 1.0 {
-      return Err("Max volume must be between 0.0 and 1.0".to_string());
+    validate_volume(max_volume)?;
+
+    let data_flow = self.device_data_flow(device_id);
+    self.config.limits_mut(data_flow).device_max_volumes.insert(device_id.to_string(), max_volume);
+    self.apply_max_volume(device_id).map(|_| ())
+  }
+
+  /// Sets several device limits at once. All values are validated before anything changes, so an
+  /// invalid entry rejects the whole batch. Limits are applied to the devices that are currently
+  /// connected; enforcement errors are left to the periodic task to report.
+  pub fn set_device_max_volumes(&mut self, max_volumes: HashMap<String, f32>) -> Result<(), String> {
+    for (device_id, max_volume) in &max_volumes {
+      validate_volume(*max_volume).map_err(|err| format!("Invalid max volume for device '{device_id}': {err}"))?;
     }
 
-    self.device_max_volumes.insert(device_id.to_string(), max_volume);
-    self.apply_max_volume(device_id)
+    for (device_id, max_volume) in max_volumes {
+      let data_flow = self.device_data_flow(&device_id);
+      self.config.limits_mut(data_flow).device_max_volumes.insert(device_id.clone(), max_volume);
+      if self.device_cache.contains_key(&device_id) {
+        let _ = self.apply_max_volume(&device_id);
+      }
+    }
+    Ok(())
+  }
+
+  /// Sets the global limit for a data flow and applies it to every connected device of that flow. A device
+  /// that can't be reached doesn't block the others; the outcome for each device is returned instead.
+  pub fn set_global_max_volume(&mut self, data_flow: DataFlow, max_volume: f32) -> Result<Vec<DeviceApplyResult>, String> {
+    validate_volume(max_volume)?;
+
+    self.config.limits_mut(data_flow).global_max_volume = max_volume;
+
+    let mut device_ids: Vec<_> = self.device_cache.iter()
+      .filter(|(_, device)| device.get_data_flow() == data_flow)
+      .map(|(device_id, _)| device_id.clone())
+      .collect();
+    device_ids.sort();
+    Ok(device_ids.into_iter()
+      .map(|device_id| {
+        let error = self.apply_max_volume(&device_id).err();
+        DeviceApplyResult { device_id, error }
+      })
+      .collect())
   }
 
-  pub fn set_global_max_volume(&mut self, max_volume: f32) -> Result<(), String> {
-    if max_volume < 0.0 || max_volume > 1.0 {
-      return Err("Max volume must be between 0.0 and 1.0".to_string());
+  /// Sets the default cap for devices of a form factor. `None` removes it.
+  pub fn set_form_factor_max_volume(&mut self, form_factor: FormFactor, max_volume: Option<f32>) -> Result<(), String> {
+    match max_volume {
+      Some(max_volume) => {
+        validate_volume(max_volume)?;
+        self.config.form_factor_max_volumes.insert(form_factor, max_volume);
+      },
+      None => {
+        self.config.form_factor_max_volumes.remove(&form_factor);
+      }
     }
-    
-    self.global_max_volume = max_volume;
+    Ok(())
+  }
 
-    let device_ids: Vec<_> = self.device_cache.keys().cloned().collect();
-    device_ids.iter().fold(Ok(()), |res, device_id| res.and(self.apply_max_volume(device_id)))
+  /// Sets the cap given to newly seen headphones. `None` disables it.
+  pub fn set_new_headphone_default_max(&mut self, max_volume: Option<f32>) -> Result<(), String> {
+    if let Some(max_volume) = max_volume {
+      validate_volume(max_volume)?;
+    }
+    self.config.new_headphone_default_max = max_volume;
+    Ok(())
+  }
+
+  pub fn set_comms_max_volume(&mut self, max_volume: Option<f32>) -> Result<(), String> {
+    if let Some(max_volume) = max_volume {
+      validate_volume(max_volume)?;
+    }
+    self.config.comms_max_volume = max_volume;
+    Ok(())
+  }
+
+  pub fn set_ducking(&mut self, priority_process: Option<String>, duck_amount: f32) -> Result<(), String> {
+    if !duck_amount.is_finite() || !(0.0..=1.0).contains(&duck_amount) {
+      return Err(format!("Duck amount must be between 0 and 1, got {duck_amount}"));
+    }
+    self.config.duck_priority_process = priority_process.filter(|process| !process.trim().is_empty());
+    self.config.duck_amount = duck_amount;
+    Ok(())
+  }
+
+  pub fn get_device_limit_enabled(&self, device_id: &str) -> bool {
+    !self.config.limit_disabled_device_ids.contains(device_id)
+  }
+
+  pub fn is_managed(&self, device_id: &str) -> bool {
+    self.config.managed_devices.as_ref().is_none_or(|managed_devices| managed_devices.contains(device_id))
+  }
+
+  /// Adds the device to the managed set. Does nothing while every device is managed.
+  pub fn add_managed_device(&mut self, device_id: &str) {
+    if let Some(managed_devices) = &mut self.config.managed_devices {
+      managed_devices.insert(device_id.to_string());
+    }
+    if self.device_cache.contains_key(device_id) {
+      let _ = self.apply_max_volume(device_id);
+    }
+  }
+
+  /// Stops managing the device. While every device is managed, this switches to managing every currently
+  /// known device except this one.
+  pub fn remove_managed_device(&mut self, device_id: &str) {
+    let managed_devices = self.config.managed_devices
+      .get_or_insert_with(|| self.device_cache.keys().cloned().collect());
+    managed_devices.remove(device_id);
+    if let Err(err) = self.release_mute(device_id) {
+      log(LogLevel::Error, format!("Couldn't unmute device '{}': {err}", self.get_device_name(device_id)));
+    }
   }
 
-  pub fn apply_max_volume(&mut self, device_id: &str) -> Result<(), String> {
+  /// Manages only `device_ids`, or every device if `None`.
+  pub fn set_managed_devices(&mut self, device_ids: Option<HashSet<String>>) {
+    self.config.managed_devices = device_ids;
+    for device_id in self.limited_device_ids() {
+      let _ = self.apply_max_volume(&device_id);
+    }
+  }
+
+  /// Lets the device go above the global limit, so only its own cap applies.
+  pub fn set_device_ignore_global(&mut self, device_id: &str, ignore_global: bool) {
+    match ignore_global {
+      true => self.config.ignore_global_device_ids.insert(device_id.to_string()),
+      false => self.config.ignore_global_device_ids.remove(device_id)
+    };
+    if !ignore_global && self.device_cache.contains_key(device_id) {
+      let _ = self.apply_max_volume(device_id);
+    }
+  }
+
+  /// Turns enforcement for the device on or off without touching its limit.
+  pub fn set_device_limit_enabled(&mut self, device_id: &str, enabled: bool) {
+    match enabled {
+      true => self.config.limit_disabled_device_ids.remove(device_id),
+      false => self.config.limit_disabled_device_ids.insert(device_id.to_string())
+    };
+    if enabled && self.device_cache.contains_key(device_id) {
+      let _ = self.apply_max_volume(device_id);
+    }
+  }
+
+  /// Pins the device to `volume`. Enforcement moves it back whenever it drifts, up or down.
+  pub fn lock_device_volume(&mut self, device_id: &str, volume: f32) -> Result<(), String> {
+    validate_volume(volume)?;
+
+    self.config.device_locked_volumes.insert(device_id.to_string(), volume);
+    if self.device_cache.contains_key(device_id) {
+      let _ = self.apply_max_volume(device_id);
+    }
+    Ok(())
+  }
+
+  pub fn unlock_device_volume(&mut self, device_id: &str) {
+    self.config.device_locked_volumes.remove(device_id);
+  }
+
+  /// Sets how often limits are enforced on the device, or `None` to use the apply interval. Intervals are
+  /// raised to `MIN_INTERVAL_MS`.
+  pub fn set_device_poll_interval(&mut self, device_id: &str, interval_ms: Option<u64>) {
+    let intervals = &mut self.config.device_poll_intervals_ms;
+    match interval_ms {
+      Some(interval_ms) => intervals.insert(device_id.to_string(), interval_ms.max(MIN_INTERVAL_MS)),
+      None => intervals.remove(device_id)
+    };
+    self.last_polled.remove(device_id);
+  }
+
+  /// Caps the device at `max_volume` for `duration` instead of its saved limits, e.g. to let one device go
+  /// loud for a song. Neither the global limit nor the other caps apply meanwhile, but a locked volume
+  /// still does. Replaces any override the device already has.
+  pub fn override_device_max_volume(
+    &mut self,
+    device_id: &str,
+    max_volume: f32,
+    duration: Duration
+  ) -> Result<(), String> {
+    validate_volume(max_volume)?;
+    if !self.device_cache.contains_key(device_id) {
+      return Err(format!("Device with ID '{}' not found", device_id));
+    }
+    let expires_at = Instant::now() + duration;
+    self.cap_overrides.insert(device_id.to_string(), CapOverride { max_volume, expires_at });
+    Ok(())
+  }
+
+  /// Reverts the device to its saved limits. Returns whether it had an override.
+  pub fn cancel_override(&mut self, device_id: &str) -> bool {
+    self.cap_overrides.remove(device_id).is_some()
+  }
+
+  /// Removes the overrides that have run out and returns the IDs of their devices.
+  pub fn take_expired_overrides(&mut self) -> Vec<String> {
+    let now = Instant::now();
+    let expired: Vec<_> = self.cap_overrides.iter()
+      .filter(|(_, cap_override)| cap_override.expires_at <= now)
+      .map(|(device_id, _)| device_id.clone())
+      .collect();
+    for device_id in &expired {
+      self.cap_overrides.remove(device_id);
+    }
+    expired
+  }
+
+  /// Whether the device's own poll interval has elapsed since limits were last enforced on it, marking it
+  /// as polled if so. Devices without one are always due.
+  pub fn take_poll_due(&mut self, device_id: &str) -> bool {
+    let Some(interval_ms) = self.config.device_poll_intervals_ms.get(device_id) else {
+      return true;
+    };
+    let interval = Duration::from_millis(*interval_ms);
+    if self.last_polled.get(device_id).is_some_and(|last_polled| last_polled.elapsed() < interval) {
+      return false;
+    }
+    self.last_polled.insert(device_id.to_string(), Instant::now());
+    true
+  }
+
+  /// Sets what happens when the device goes over its cap. A device muted under the `Mute` policy is
+  /// unmuted when switching away from it.
+  pub fn set_device_exceed_policy(&mut self, device_id: &str, policy: ExceedPolicy) -> Result<(), String> {
+    match policy {
+      ExceedPolicy::Lower => self.config.device_exceed_policies.remove(device_id),
+      ExceedPolicy::Mute => self.config.device_exceed_policies.insert(device_id.to_string(), policy)
+    };
+    if policy == ExceedPolicy::Lower && self.muted_device_ids.remove(device_id) {
+      self.set_device_mute(device_id, false)?;
+    }
+    Ok(())
+  }
+
+  /// Sets a display alias for the device. An empty alias removes it.
+  pub fn set_device_alias(&mut self, device_id: &str, alias: &str) {
+    let alias = alias.trim();
+    match alias.is_empty() {
+      true => self.config.device_aliases.remove(device_id),
+      false => self.config.device_aliases.insert(device_id.to_string(), alias.to_string())
+    };
+  }
+
+  pub fn set_device_mute(&mut self, device_id: &str, muted: bool) -> Result<(), String> {
     let device = self.device_cache.get_mut(device_id)
       .ok_or_else(|| format!("Device with ID '{}' not found", device_id))?;
 
-    let device_volume = device.get_volume()?;
-    let max_volume = match self.device_max_volumes.get(device_id) {
-      Some(volume) => f32::min(*volume, self.global_max_volume),
-      None => self.global_max_volume,
+    device.set_mute(muted)
+  }
+
+  /// Whether the periodic enforcement should touch the device right now.
+  ///
+  /// With `enforce_only_when_playing` set, devices whose peak meter reads as silence are skipped. This
+  /// saves COM calls on idle devices, but it also means a user can raise the volume past the limit while
+  /// nothing is playing, and the limit only kicks in once sound starts. The first moments of playback
+  /// may therefore be louder than the limit, which is why the option is off by default.
+  pub fn should_enforce(&self, device_id: &str) -> Result<bool, String> {
+    // A device muted under the `Mute` policy reads as silent, but still has to be unmuted once it's back
+    // under its cap.
+    if !self.config.enforce_only_when_playing || self.muted_device_ids.contains(device_id) {
+      return Ok(true);
+    }
+
+    let device = self.device_cache.get(device_id)
+      .ok_or_else(|| format!("Device with ID '{}' not found", device_id))?;
+
+    Ok(device.get_peak_value()? > PLAYING_PEAK_THRESHOLD)
+  }
+
+  /// The cap that enforcement applies to the device. The device's own limit is its specific limit if it
+  /// has one, otherwise the default for its form factor; the cap is the stricter of that and the global
+  /// limit, unless the device ignores the global limit. Both the specific and global limits come from the
+  /// namespace of the device's data flow. While the device is the default communications device,
+  /// `comms_max_volume` applies on top, whether or not it's also the multimedia default. An override
+  /// replaces all of that until it expires.
+  pub fn effective_max_volume(&self, device_id: &str) -> f32 {
+    if !self.get_device_limit_enabled(device_id) {
+      return 1.0;
+    }
+    let cap_override = self.cap_overrides.get(device_id)
+      .filter(|cap_override| cap_override.expires_at > Instant::now());
+    if let Some(cap_override) = cap_override {
+      return cap_override.max_volume;
+    }
+
+    let data_flow = self.device_data_flow(device_id);
+    let limits = self.config.limits(data_flow);
+    let device_max_volume = limits.device_max_volumes.get(device_id).or_else(|| {
+      let form_factor = self.device_cache.get(device_id)?.get_form_factor();
+      self.config.form_factor_max_volumes.get(&form_factor)
+    });
+
+    let global_max_volume = match self.config.ignore_global_device_ids.contains(device_id) {
+      true => 1.0,
+      false => limits.global_max_volume
+    };
+    let max_volume = match device_max_volume {
+      Some(volume) => f32::min(*volume, global_max_volume),
+      None => global_max_volume,
     };
+    match self.config.comms_max_volume {
+      Some(comms_max_volume) if self.is_default_device(device_id, data_flow, DeviceRole::Communications) => {
+        f32::min(max_volume, comms_max_volume)
+      },
+      _ => max_volume
+    }
+  }
 
-    if device_volume > max_volume {
-      device.set_volume(max_volume)?;
+  /// The volume the device is pinned to, if it's locked and its limits are enabled.
+  pub fn locked_volume(&self, device_id: &str) -> Option<f32> {
+    match self.get_device_limit_enabled(device_id) {
+      true => self.config.device_locked_volumes.get(device_id).copied(),
+      false => None
     }
+  }
 
-    Ok(())
+  /// Reads the volume of every active device and returns those that changed since the last call.
+  /// Devices seen for the first time aren't reported.
+  pub fn take_volume_changes(&mut self) -> Vec<VolumeChange> {
+    let volumes: HashMap<_, _> = self.device_cache.iter()
+      .filter(|(_, device)| device.get_state() == Ok(DeviceState::Active))
+      .filter_map(|(device_id, device)| Some((device_id.clone(), device.get_volume().ok()?)))
+      .collect();
+
+    let mut changes: Vec<_> = volumes.iter()
+      .filter(|(device_id, volume)| {
+        self.last_volumes.get(*device_id).is_some_and(|last_volume| (*volume - last_volume).abs() > VOLUME_EPSILON)
+      })
+      .map(|(device_id, volume)| VolumeChange { device_id: device_id.clone(), volume: *volume })
+      .collect();
+    changes.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    self.last_volumes = volumes;
+    changes
+  }
+
+  /// How many devices were enumerated by the last update.
+  pub fn device_count(&self) -> usize {
+    self.device_cache.len()
+  }
+
+  /// Whether any limit could need enforcing, judging by the config and overrides alone so it's cheap to
+  /// check every tick. When this is false, enforcement would only read volumes to find nothing to do. A
+  /// device still muted under the `Mute` policy counts, since it has to be unmuted.
+  pub fn has_limits(&self) -> bool {
+    let below_max = |volume: &f32| *volume < 1.0;
+    !self.muted_device_ids.is_empty() || self.config.limiting_enabled && (
+      [&self.config.render, &self.config.capture].into_iter().any(|limits| {
+        below_max(&limits.global_max_volume) || limits.device_max_volumes.values().any(below_max)
+      })
+        || self.config.form_factor_max_volumes.values().any(below_max)
+        || self.config.comms_max_volume.as_ref().is_some_and(below_max)
+        || !self.config.device_locked_volumes.is_empty()
+        || self.cap_overrides.values().any(|cap_override| below_max(&cap_override.max_volume))
+    )
+  }
+
+  /// IDs of active devices that have a cap below 100% or a locked volume, and of devices muted under the
+  /// `Mute` policy, which enforcement unmutes once they no longer need it. Other devices need no enforcement.
+  pub fn limited_device_ids(&self) -> Vec<String> {
+    self.device_cache.iter()
+      .filter(|(device_id, device)| self.muted_device_ids.contains(*device_id) || (
+        device.get_state() == Ok(DeviceState::Active)
+          && self.is_managed(device_id)
+          && (self.effective_max_volume(device_id) < 1.0 || self.locked_volume(device_id).is_some())
+      ))
+      .map(|(device_id, _)| device_id.clone())
+      .collect()
+  }
+
+  pub fn get_device_sessions(&self, device_id: &str) -> Result<Vec<SessionInfo>, String> {
+    self.device_cache.get(device_id)
+      .ok_or_else(|| format!("Device with ID '{}' not found", device_id))?
+      .get_sessions()
+  }
+
+  /// Lowers every other output session while the priority process is playing, and restores them to their
+  /// pre-duck volumes once it goes quiet. Sessions that ended while ducked are forgotten. Returns the
+  /// errors of the devices and sessions that couldn't be handled.
+  pub fn apply_ducking(&mut self) -> Vec<String> {
+    if self.config.duck_priority_process.is_none() && self.ducked_sessions.is_empty() {
+      return Vec::new();
+    }
+
+    let mut errors = Vec::new();
+    let mut sessions = Vec::new();
+    for (device_id, device) in &self.device_cache {
+      if device.get_data_flow() != DataFlow::Render || device.get_state() != Ok(DeviceState::Active) {
+        continue;
+      }
+      match device.get_sessions() {
+        Ok(device_sessions) => sessions.extend(device_sessions.into_iter().map(|session| (device_id.clone(), session))),
+        Err(err) => errors.push(format!("Couldn't read sessions of device '{}': {err}", self.get_device_name(device_id)))
+      }
+    }
+
+    let is_priority = |session: &SessionInfo| match (&self.config.duck_priority_process, &session.process_name) {
+      (Some(priority_process), Some(process_name)) => process_name.eq_ignore_ascii_case(priority_process),
+      _ => false
+    };
+    let priority_playing = sessions.iter().any(|(_, session)| is_priority(session) && session.peak > PLAYING_PEAK_THRESHOLD);
+    let to_duck: Vec<_> = match priority_playing {
+      true => sessions.iter()
+        .filter(|(_, session)| !is_priority(session) && !self.ducked_sessions.contains_key(&session.instance_id))
+        .cloned()
+        .collect(),
+      false => Vec::new()
+    };
+
+    self.ducked_sessions.retain(|instance_id, _| sessions.iter().any(|(_, session)| &session.instance_id == instance_id));
+    match priority_playing {
+      true => {
+        let duck_amount = self.config.duck_amount;
+        for (device_id, session) in to_duck {
+          match self.set_session_volume(&device_id, &session.instance_id, session.volume * (1.0 - duck_amount)) {
+            Ok(()) => {
+              self.ducked_sessions.insert(session.instance_id, DuckedSession { device_id, volume: session.volume });
+            },
+            Err(err) => errors.push(format!("Couldn't duck {}: {err}", session.display_name))
+          }
+        }
+      },
+      false => errors.extend(self.restore_ducked_sessions())
+    }
+    errors
+  }
+
+  /// Puts every ducked session back at its pre-duck volume. Returns the errors of the ones that couldn't be.
+  pub fn restore_ducked_sessions(&mut self) -> Vec<String> {
+    let ducked_sessions: Vec<_> = self.ducked_sessions.drain().collect();
+    ducked_sessions.into_iter()
+      .filter_map(|(instance_id, ducked)| {
+        self.set_session_volume(&ducked.device_id, &instance_id, ducked.volume)
+          .err()
+          .map(|err| format!("Couldn't restore a ducked session: {err}"))
+      })
+      .collect()
+  }
+
+  fn set_session_volume(&mut self, device_id: &str, instance_id: &str, volume: f32) -> Result<(), String> {
+    self.device_cache.get_mut(device_id)
+      .ok_or_else(|| format!("Device with ID '{}' not found", device_id))?
+      .set_session_volume(instance_id, volume)
+  }
+
+  /// Undoes temporary changes to other apps and devices before the app exits.
+  pub fn shutdown(&mut self) {
+    for err in self.restore_ducked_sessions() {
+      log(LogLevel::Error, err);
+    }
+    self.release_mutes();
+  }
+
+  /// Unmutes the device if it was muted under the `Mute` policy. A device that's no longer connected is
+  /// just forgotten.
+  fn release_mute(&mut self, device_id: &str) -> Result<(), String> {
+    if !self.muted_device_ids.remove(device_id) {
+      return Ok(());
+    }
+    match self.device_cache.get_mut(device_id) {
+      Some(device) => device.set_mute(false),
+      None => Ok(())
+    }
+  }
+
+  /// Unmutes every device muted under the `Mute` policy, logging the ones that can't be reached.
+  fn release_mutes(&mut self) {
+    let device_ids: Vec<_> = self.muted_device_ids.iter().cloned().collect();
+    for device_id in device_ids {
+      if let Err(err) = self.release_mute(&device_id) {
+        log(LogLevel::Error, format!("Couldn't unmute device '{}': {err}", self.get_device_name(&device_id)));
+      }
+    }
+  }
+
+  /// The name to use for the device in messages: its alias or friendly name, falling back to the last name
+  /// read from it and then to the ID.
+  pub fn get_device_name(&self, device_id: &str) -> String {
+    if let Some(alias) = self.config.device_aliases.get(device_id) {
+      return alias.clone();
+    }
+    self.device_cache.get(device_id)
+      .and_then(|device| device.get_name().ok())
+      .or_else(|| self.device_names.get(device_id).cloned())
+      .unwrap_or_else(|| device_id.to_string())
+  }
+
+  /// Brings the device's volume down to its cap if it's above it, or back to its locked volume if it has
+  /// drifted from it in either direction. A locked volume takes precedence over the caps. In `WarnOnly`
+  /// mode the volume is left alone, and only the first check that finds the device off limits reports it.
+  /// With `max_step_per_tick` set, the volume moves at most that far per call, reaching the target
+  /// exactly on the last step. A device muted under the `Mute` policy is unmuted once enforcement no
+  /// longer applies to it or it's back under its cap. Returns what was done, if anything.
+  pub fn apply_max_volume(&mut self, device_id: &str) -> Result<Option<Enforcement>, String> {
+    let Some(check) = self.check_limit(device_id)? else {
+      self.release_mute(device_id)?;
+      return Ok(None);
+    };
+    let exceed_policy = self.config.device_exceed_policies.get(device_id).copied().unwrap_or_default();
+    let mutes = self.config.mode == LimitMode::Enforce && !check.locked && exceed_policy == ExceedPolicy::Mute;
+    if !mutes {
+      self.release_mute(device_id)?;
+    }
+    let device = self.device_cache.get_mut(device_id)
+      .ok_or_else(|| format!("Device with ID '{}' not found", device_id))?;
+
+    let device_volume = check.volume;
+    let Some(max_volume) = check.target_volume else {
+      self.warned_device_ids.remove(device_id);
+      self.release_mute(device_id)?;
+      if log_enabled(LogLevel::Debug) {
+        let device_name = self.get_device_name(device_id);
+        log(LogLevel::Debug, format!("{device_name}: volume {device_volume}, cap {}, within limit", check.max_volume));
+      }
+      return Ok(None);
+    };
+
+    let action = match self.config.mode {
+      LimitMode::Enforce if mutes => {
+        if device.get_mute()? {
+          return Ok(None);
+        }
+        device.set_mute(true)?;
+        self.muted_device_ids.insert(device_id.to_string());
+        EnforcementAction::Muted
+      },
+      LimitMode::Enforce => {
+        let step = self.config.max_step_per_tick;
+        let volume = match step > 0.0 && (device_volume - max_volume).abs() > step {
+          true => device_volume - step.copysign(device_volume - max_volume),
+          false => max_volume
+        };
+        device.set_volume(volume)?;
+        EnforcementAction::Corrected
+      },
+      LimitMode::WarnOnly => {
+        if !self.warned_device_ids.insert(device_id.to_string()) {
+          return Ok(None);
+        }
+        EnforcementAction::Warned
+      }
+    };
+    let enforcement = Enforcement {
+      device_id: device_id.to_string(),
+      device_name: self.get_device_name(device_id),
+      volume: device_volume,
+      max_volume,
+      action
+    };
+    let level = match action {
+      EnforcementAction::Corrected | EnforcementAction::Muted => LogLevel::Info,
+      EnforcementAction::Warned => LogLevel::Debug
+    };
+    log(level, format!("{}: volume {device_volume}, cap {max_volume}, {action:?}", enforcement.device_name));
+    Ok(Some(enforcement))
+  }
+
+  /// Reports what `apply_max_volume` would do to every active device, without changing any device or
+  /// the enforcement state.
+  pub fn simulate_apply(&self) -> Vec<SimResult> {
+    let mut results: Vec<_> = self.device_cache.iter()
+      .filter(|(_, device)| device.get_state().is_ok_and(|state| state == DeviceState::Active))
+      .filter_map(|(device_id, _)| match self.simulate_device(device_id) {
+        Ok(result) => Some(result),
+        Err(err) => {
+          log(LogLevel::Error, err);
+          None
+        }
+      })
+      .collect();
+    results.sort_by(|a, b| a.device_name.cmp(&b.device_name).then_with(|| a.device_id.cmp(&b.device_id)));
+    results
+  }
+
+  fn simulate_device(&self, device_id: &str) -> Result<SimResult, String> {
+    let device = self.device_cache.get(device_id)
+      .ok_or_else(|| format!("Device with ID '{}' not found", device_id))?;
+    let exceed_policy = self.config.device_exceed_policies.get(device_id).copied().unwrap_or_default();
+
+    let (volume, max_volume, action) = match self.check_limit(device_id)? {
+      Some(check) if check.target_volume.is_some() => {
+        let action = match self.config.mode {
+          LimitMode::Enforce if !check.locked && exceed_policy == ExceedPolicy::Mute => {
+            (!device.get_mute()?).then_some(EnforcementAction::Muted)
+          },
+          LimitMode::Enforce => Some(EnforcementAction::Corrected),
+          LimitMode::WarnOnly => Some(EnforcementAction::Warned)
+        };
+        (check.volume, check.max_volume, action)
+      },
+      Some(check) => (check.volume, check.max_volume, None),
+      None => (device.get_volume()?, self.effective_max_volume(device_id), None)
+    };
+    Ok(SimResult {
+      device_id: device_id.to_string(),
+      device_name: self.get_device_name(device_id),
+      volume,
+      max_volume,
+      action
+    })
+  }
+
+  /// Reads the device's volume and works out where enforcement has to move it. `None` if enforcement
+  /// leaves the device alone: limiting is off, the device isn't managed or active, or it has no cap.
+  /// Shared by `apply_max_volume` and `simulate_apply`, so a simulation resolves caps the same way.
+  fn check_limit(&self, device_id: &str) -> Result<Option<LimitCheck>, String> {
+    let locked_volume = self.locked_volume(device_id);
+    let max_volume = self.effective_max_volume(device_id);
+    let device = self.device_cache.get(device_id)
+      .ok_or_else(|| format!("Device with ID '{}' not found", device_id))?;
+
+    if !self.config.limiting_enabled || !self.is_managed(device_id) || device.get_state()? != DeviceState::Active {
+      return Ok(None);
+    }
+    if locked_volume.is_none() && max_volume >= 1.0 {
+      return Ok(None);
+    }
+
+    let volume = device.get_volume()?;
+    let target_volume = match locked_volume {
+      Some(locked_volume) => ((volume - locked_volume).abs() > VOLUME_EPSILON).then_some(locked_volume),
+      None => (volume - max_volume > VOLUME_EPSILON).then_some(max_volume)
+    };
+    Ok(Some(LimitCheck {
+      volume,
+      max_volume: locked_volume.unwrap_or(max_volume),
+      locked: locked_volume.is_some(),
+      target_volume
+    }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn controller(config: AudioDeviceConfig) -> AudioController {
+    let mut controller = AudioController::init(config, GUID::zeroed()).unwrap();
+    controller.update_devices().unwrap();
+    controller
+  }
+
+  #[test]
+  fn validate_volume_rejects_non_finite_and_out_of_range_volumes() {
+    for volume in [0.0, 0.5, 1.0] {
+      assert!(validate_volume(volume).is_ok());
+    }
+    for volume in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -0.01, 1.01] {
+      assert!(validate_volume(volume).is_err());
+    }
+  }
+
+  #[test]
+  fn set_device_max_volumes_applies_every_limit() {
+    mock::add_device("a", "Speakers", 0.8);
+    mock::add_device("b", "Headphones", 0.8);
+    let mut controller = controller(AudioDeviceConfig::default());
+
+    controller.set_device_max_volumes(HashMap::from([("a".to_string(), 0.5), ("b".to_string(), 0.9)])).unwrap();
+
+    assert_eq!(controller.config.render.device_max_volumes["a"], 0.5);
+    assert_eq!(controller.config.render.device_max_volumes["b"], 0.9);
+    assert_eq!(mock::device("a").volume, 0.5);
+    assert_eq!(mock::device("b").set_volume_calls, 0);
+  }
+
+  #[test]
+  fn set_device_max_volumes_rejects_the_whole_batch_on_an_invalid_value() {
+    mock::add_device("a", "Speakers", 0.8);
+    let mut controller = controller(AudioDeviceConfig::default());
+
+    for invalid in [1.5, -0.1, f32::NAN] {
+      let max_volumes = HashMap::from([("a".to_string(), 0.5), ("b".to_string(), invalid)]);
+      assert!(controller.set_device_max_volumes(max_volumes).is_err());
+    }
+
+    assert!(controller.config.render.device_max_volumes.is_empty());
+    assert_eq!(mock::device("a").set_volume_calls, 0);
+  }
+
+  #[test]
+  fn new_device_defaults_only_change_the_config_for_unseen_devices() {
+    mock::add_device("speakers", "Speakers", 1.0);
+    mock::add_device("headphones", "Headphones", 1.0);
+    mock::add_device("headset-mic", "Headset Microphone", 1.0);
+    mock::update_device("headphones", |device| device.form_factor = FormFactor::Headphones);
+    mock::update_device("headset-mic", |device| {
+      device.form_factor = FormFactor::Headset;
+      device.data_flow = DataFlow::Capture;
+    });
+    let config = AudioDeviceConfig { new_headphone_default_max: Some(0.4), ..AudioDeviceConfig::default() };
+    let mut controller = controller(config);
+    let device_ids = vec!["headphones".to_string(), "headset-mic".to_string(), "speakers".to_string()];
+
+    let (changed, limited) = controller.apply_new_device_defaults(&device_ids);
+    assert!(changed);
+    assert_eq!(limited, vec!["headphones".to_string()]);
+    assert_eq!(controller.config.render.device_max_volumes["headphones"], 0.4);
+
+    controller.config.render.device_max_volumes.clear();
+    let (changed, limited) = controller.apply_new_device_defaults(&device_ids);
+    assert!(!changed);
+    assert!(limited.is_empty());
+    assert!(controller.config.render.device_max_volumes.is_empty());
+  }
+
+  #[test]
+  fn devices_with_the_same_name_are_told_apart() {
+    mock::add_device("{0.0.0.00000000}.{aaaa1111}", "Speakers", 1.0);
+    mock::add_device("{0.0.0.00000000}.{bbbb2222}", "Speakers", 1.0);
+    mock::add_device("{0.0.0.00000000}.{cccc3333}", "Headphones", 1.0);
+    let controller = controller(AudioDeviceConfig::default());
+
+    let display_names: Vec<_> = controller.get_devices().into_iter().map(|device| device.display_name).collect();
+    assert_eq!(display_names, vec!["Headphones", "Speakers (1111)", "Speakers (2222)"]);
+  }
+
+  #[test]
+  fn enabling_a_listed_device_reports_it_as_activated() {
+    mock::add_device("a", "Speakers", 1.0);
+    mock::add_device("b", "Headphones", 1.0);
+    mock::update_device("a", |device| device.state = DeviceState::Disabled);
+    let config = AudioDeviceConfig { include_disabled_devices: true, ..AudioDeviceConfig::default() };
+    let mut controller = controller(config);
+
+    mock::update_device("a", |device| device.state = DeviceState::Active);
+    let changes = controller.update_devices().unwrap();
+    assert_eq!(changes.activated, vec!["a".to_string()]);
+    assert!(changes.added.is_empty());
+    assert!(changes.is_changed());
+
+    let changes = controller.update_devices().unwrap();
+    assert!(changes.activated.is_empty());
+    assert!(!changes.is_changed());
+  }
+
+  #[test]
+  fn reinitialize_reopens_invalidated_devices() {
+    mock::add_device("a", "Speakers", 0.8);
+    mock::add_device("b", "Headphones", 0.8);
+    let mut config = AudioDeviceConfig::default();
+    config.render.global_max_volume = 0.5;
+    let mut controller = controller(config);
+
+    mock::update_device("a", |device| device.invalidated = true);
+    assert!(!controller.take_rebuild_due());
+    mock::update_device("b", |device| device.invalidated = true);
+    assert!(controller.take_rebuild_due());
+    assert!(controller.apply_max_volume("a").is_err());
+
+    let changes = controller.reinitialize().unwrap();
+    assert_eq!(changes.unchanged, vec!["a".to_string(), "b".to_string()]);
+    assert!(changes.added.is_empty() && changes.removed.is_empty());
+    assert_eq!(mock::device("a").opens, 2);
+    assert_eq!(mock::device("b").opens, 2);
+
+    assert!(controller.apply_max_volume("a").unwrap().is_some());
+    assert_eq!(mock::device("a").volume, 0.5);
+    assert!(!controller.take_rebuild_due());
+  }
+
+  #[test]
+  fn simulate_apply_reports_without_changing_devices() {
+    mock::add_device("over", "Over cap", 0.8);
+    mock::add_device("under", "Under cap", 0.3);
+    mock::add_device("muted", "Mute policy", 0.9);
+    let mut config = AudioDeviceConfig::default();
+    config.render.global_max_volume = 0.5;
+    config.device_exceed_policies.insert("muted".to_string(), ExceedPolicy::Mute);
+    let mut controller = controller(config);
+
+    let results = controller.simulate_apply();
+    let actions: Vec<_> = results.iter().map(|result| (result.device_id.as_str(), result.action)).collect();
+    assert_eq!(actions, vec![
+      ("muted", Some(EnforcementAction::Muted)),
+      ("over", Some(EnforcementAction::Corrected)),
+      ("under", None)
+    ]);
+    assert_eq!(results[1].volume, 0.8);
+    assert_eq!(results[1].max_volume, 0.5);
+    assert_eq!(mock::device("over").set_volume_calls, 0);
+    assert!(!mock::device("muted").muted);
+
+    // Unlike enforcement, which warns once per crossing, a simulation reports the warning every time.
+    controller.set_mode(LimitMode::WarnOnly);
+    controller.apply_max_volume("over").unwrap();
+    let results = controller.simulate_apply();
+    assert_eq!(results[1].action, Some(EnforcementAction::Warned));
+    assert_eq!(mock::device("over").volume, 0.8);
+  }
+
+  #[test]
+  fn change_config_only_reports_actual_changes() {
+    let mut controller = controller(AudioDeviceConfig::default());
+    let set_global = |max_volume: f32| {
+      move |controller: &mut AudioController| controller.set_global_max_volume(DataFlow::Render, max_volume)
+    };
+
+    let (_, previous_config) = controller.change_config(set_global(1.0)).unwrap();
+    assert!(previous_config.is_none());
+
+    let (_, previous_config) = controller.change_config(set_global(0.5)).unwrap();
+    assert_eq!(previous_config.map(|config| config.render.global_max_volume), Some(1.0));
+    assert_eq!(controller.config.render.global_max_volume, 0.5);
+
+    assert!(controller.change_config(set_global(f32::NAN)).is_err());
+  }
+
+  #[test]
+  fn devices_at_their_cap_are_left_alone() {
+    mock::add_device("at", "At cap", 0.5);
+    mock::add_device("near", "Near cap", 0.503);
+    mock::add_device("over", "Over cap", 0.6);
+    let mut config = AudioDeviceConfig::default();
+    config.render.global_max_volume = 0.5;
+    let mut controller = controller(config);
+
+    for _ in 0..3 {
+      for device_id in ["at", "near", "over"] {
+        controller.apply_max_volume(device_id).unwrap();
+      }
+    }
+
+    assert_eq!(mock::device("at").set_volume_calls, 0);
+    assert_eq!(mock::device("near").set_volume_calls, 0);
+    assert_eq!(mock::device("over").set_volume_calls, 1);
+    assert_eq!(mock::device("over").volume, 0.5);
+  }
+
+  #[test]
+  fn devices_muted_over_their_cap_are_unmuted_once_limiting_lets_go() {
+    mock::add_device("a", "Speakers", 0.8);
+    let mut config = AudioDeviceConfig::default();
+    config.render.global_max_volume = 0.5;
+    let mut controller = controller(config);
+    controller.set_device_exceed_policy("a", ExceedPolicy::Mute).unwrap();
+
+    controller.apply_max_volume("a").unwrap();
+    assert!(mock::device("a").muted);
+    controller.set_global_max_volume(DataFlow::Render, 1.0).unwrap();
+    assert!(!mock::device("a").muted);
+
+    controller.set_global_max_volume(DataFlow::Render, 0.5).unwrap();
+    assert!(mock::device("a").muted);
+    controller.set_limiting_enabled(false);
+    assert!(!mock::device("a").muted);
+
+    controller.set_limiting_enabled(true);
+    assert!(mock::device("a").muted);
+    controller.set_mode(LimitMode::WarnOnly);
+    assert!(!mock::device("a").muted);
+
+    controller.set_mode(LimitMode::Enforce);
+    controller.apply_max_volume("a").unwrap();
+    assert!(mock::device("a").muted);
+    controller.remove_managed_device("a");
+    assert!(!mock::device("a").muted);
+
+    controller.add_managed_device("a");
+    assert!(mock::device("a").muted);
+    controller.shutdown();
+    assert!(!mock::device("a").muted);
+  }
+
+  #[test]
+  fn profiles_follow_their_devices_and_fall_back_to_the_one_picked_by_hand() {
+    mock::add_device("speakers", "Speakers", 0.2);
+    mock::add_device("headset", "Headset", 0.2);
+    mock::update_device("headset", |device| device.state = DeviceState::Unplugged);
+    let mut controller = controller(AudioDeviceConfig::default());
+    let save_profile = |controller: &mut AudioController, name: &str, max_volume: f32, devices: &[&str]| {
+      controller.set_global_max_volume(DataFlow::Render, max_volume).unwrap();
+      controller.save_profile(name, devices.iter().map(|device_id| device_id.to_string()).collect()).unwrap();
+    };
+    save_profile(&mut controller, "Desk", 0.8, &[]);
+    save_profile(&mut controller, "Headset", 0.3, &["headset"]);
+    save_profile(&mut controller, "Both", 0.5, &["speakers", "headset"]);
+    controller.activate_profile("Desk").unwrap();
+    let plug_headset = |controller: &mut AudioController, state: DeviceState| {
+      mock::update_device("headset", |device| device.state = state);
+      controller.update_devices().unwrap();
+      controller.auto_activate_profile()
+    };
+
+    assert_eq!(plug_headset(&mut controller, DeviceState::Active), Some("Headset".to_string()));
+    assert_eq!(controller.get_global_max_volume(DataFlow::Render), 0.3);
+
+    // Picking a profile by hand holds while the same devices stay connected.
+    controller.activate_profile("Desk").unwrap();
+    assert_eq!(plug_headset(&mut controller, DeviceState::Active), None);
+    assert_eq!(controller.get_global_max_volume(DataFlow::Render), 0.8);
+
+    controller.set_profile_order(vec!["Both".to_string()]);
+    assert_eq!(plug_headset(&mut controller, DeviceState::Unplugged), None);
+    assert_eq!(plug_headset(&mut controller, DeviceState::Active), Some("Both".to_string()));
+    assert_eq!(controller.get_global_max_volume(DataFlow::Render), 0.5);
+    assert_eq!(plug_headset(&mut controller, DeviceState::Unplugged), Some("Desk".to_string()));
+    assert_eq!(controller.get_global_max_volume(DataFlow::Render), 0.8);
   }
 }
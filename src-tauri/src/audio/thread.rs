@@ -0,0 +1,186 @@
+//! Owns the `AudioController` on a dedicated COM thread.
+//!
+//! WASAPI objects are apartment-threaded and must only be used from the thread that created them, so the
+//! controller is created and used exclusively on one thread initialized with `CoInitializeEx`. Commands and
+//! periodic tasks send jobs to that thread over a channel and await the result.
+//!
+//! The threading model is configurable with `com_apartment`, as some drivers behave better under one than
+//! the other:
+//!
+//! - A single-threaded apartment (the default) matches how the WASAPI objects are used, as nothing else
+//!   ever touches them. `IMMNotificationClient` callbacks arrive on system threads in either model, so
+//!   they stay compatible as long as they only forward work to this thread. The catch is that this thread
+//!   blocks on its channel rather than pumping messages, so a driver component that calls back into the
+//!   apartment can stall until the next job.
+//! - A multithreaded apartment needs no message pump and lets callbacks reach the objects directly.
+//!   Apartment-threaded driver components are then hosted in a separate apartment, though, so every call
+//!   into them is marshalled, which is slower and, with some drivers, fails with COM threading errors.
+//!
+//! On shutdown the controller, and every interface it holds, is dropped before
+//! `CoUninitialize` so nothing is released into a torn-down apartment.
+
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use windows::Win32::System::Com::{CoInitializeEx, CoUninitialize, COINIT_APARTMENTTHREADED, COINIT_MULTITHREADED};
+
+use crate::audio::{AudioController, ComApartment};
+use crate::logging::{log, LogLevel};
+
+/// How long `shutdown` waits for the thread to release COM before giving up on it.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+type Job = Box<dyn FnOnce(&mut AudioController) + Send>;
+
+enum Message {
+  Job(Job),
+  /// Stop the thread, then signal on the sender once COM has been uninitialized.
+  Shutdown(mpsc::Sender<()>)
+}
+
+#[derive(Clone)]
+pub struct AudioThread {
+  sender: mpsc::Sender<Message>
+}
+
+impl AudioThread {
+  /// Spawns the audio thread in `apartment` and runs `init` on it. Returns once the controller has been created.
+  pub fn spawn(
+    apartment: ComApartment,
+    init: impl FnOnce() -> Result<AudioController, String> + Send + 'static
+  ) -> Result<Self, String> {
+    let (sender, receiver) = mpsc::channel::<Message>();
+    let (init_sender, init_receiver) = mpsc::channel::<Result<(), String>>();
+
+    std::thread::Builder::new()
+      .name("audio".to_string())
+      .spawn(move || {
+        let coinit = match apartment {
+          ComApartment::Sta => COINIT_APARTMENTTHREADED,
+          ComApartment::Mta => COINIT_MULTITHREADED
+        };
+        if let Err(err) = unsafe { CoInitializeEx(None, coinit) }.ok() {
+          let _ = init_sender.send(Err(format!("Couldn't initialize COM ({apartment:?}): {err}")));
+          return;
+        }
+        log(LogLevel::Info, format!("Audio thread started in {apartment:?} apartment"));
+
+        let mut controller = match init() {
+          Ok(controller) => controller,
+          Err(err) => {
+            let _ = init_sender.send(Err(err));
+            unsafe { CoUninitialize() };
+            return;
+          }
+        };
+        let _ = init_sender.send(Ok(()));
+
+        let mut done_sender = None;
+        while let Ok(message) = receiver.recv() {
+          match message {
+            Message::Job(job) => job(&mut controller),
+            Message::Shutdown(sender) => {
+              done_sender = Some(sender);
+              break;
+            }
+          }
+        }
+
+        controller.shutdown();
+        drop(controller);
+        unsafe { CoUninitialize() };
+        if let Some(done_sender) = done_sender {
+          let _ = done_sender.send(());
+        }
+      })
+      .map_err(|err| format!("Couldn't spawn audio thread: {err}"))?;
+
+    init_receiver
+      .recv()
+      .map_err(|_| "Audio thread exited during initialization".to_string())??;
+
+    Ok(AudioThread { sender })
+  }
+
+  fn send<R: Send + 'static>(
+    &self,
+    job: impl FnOnce(&mut AudioController) -> R + Send + 'static
+  ) -> Result<oneshot::Receiver<R>, String> {
+    let (result_sender, result_receiver) = oneshot::channel();
+    self.sender
+      .send(Message::Job(Box::new(move |controller| {
+        let _ = result_sender.send(job(controller));
+      })))
+      .map_err(|_| "Audio thread is not running".to_string())?;
+    Ok(result_receiver)
+  }
+
+  /// Runs `job` on the audio thread and awaits its result.
+  pub async fn call<R: Send + 'static>(
+    &self,
+    job: impl FnOnce(&mut AudioController) -> R + Send + 'static
+  ) -> Result<R, String> {
+    self.send(job)?
+      .await
+      .map_err(|_| "Audio thread dropped the request".to_string())
+  }
+
+  /// Stops the thread once the jobs queued before this have run, and waits for it to release its devices
+  /// and uninitialize COM. Jobs sent afterwards fail.
+  pub fn shutdown(&self) {
+    let (done_sender, done_receiver) = mpsc::channel();
+    if self.sender.send(Message::Shutdown(done_sender)).is_ok() {
+      let _ = done_receiver.recv_timeout(SHUTDOWN_TIMEOUT);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use windows::core::GUID;
+
+  use super::*;
+  use crate::audio::{mock, AudioDeviceConfig};
+
+  #[test]
+  fn device_updates_and_enforcement_never_interleave() {
+    let audio = AudioThread::spawn(ComApartment::Mta, || {
+      // The mock devices are thread-local, so they're added on the audio thread.
+      mock::add_device("a", "Speakers", 0.8);
+      let mut config = AudioDeviceConfig::default();
+      config.render.global_max_volume = 0.5;
+      AudioController::init(config, GUID::zeroed())
+    }).unwrap();
+    let in_job = Arc::new(AtomicBool::new(false));
+
+    // Callers on several threads queue cache swaps and applies at the same time. Each job checks that no other
+    // one is running, and every apply finds the device, whichever swap it was queued behind.
+    let callers: Vec<_> = (0..4).map(|caller| {
+      let audio = audio.clone();
+      let in_job = in_job.clone();
+      std::thread::spawn(move || {
+        (0..25).map(|index| {
+          let in_job = in_job.clone();
+          audio.send(move |controller| {
+            assert!(!in_job.swap(true, Ordering::SeqCst));
+            let result = match (caller + index) % 2 {
+              0 => controller.reinitialize().map(|_| ()),
+              _ => controller.apply_max_volume("a").map(|_| ())
+            };
+            in_job.store(false, Ordering::SeqCst);
+            result
+          }).unwrap()
+        }).collect::<Vec<_>>()
+      })
+    }).collect();
+
+    for caller in callers {
+      for result in caller.join().unwrap() {
+        assert_eq!(result.blocking_recv(), Ok(Ok(())));
+      }
+    }
+    audio.shutdown();
+  }
+}
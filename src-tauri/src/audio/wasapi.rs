@@ -1,59 +1,356 @@
+use std::cell::RefCell;
+use std::time::Duration;
+use windows::core::{Interface, GUID, HRESULT, HSTRING, PWSTR};
 use windows::Win32::Devices::FunctionDiscovery::{PKEY_DeviceInterface_FriendlyName};
-use windows::Win32::Foundation::PROPERTYKEY;
-use windows::Win32::Media::Audio::Endpoints::IAudioEndpointVolume;
-use windows::Win32::Media::Audio::{DEVICE_STATE_ACTIVE, IMMDevice, IMMDeviceCollection, IMMDeviceEnumerator, MMDeviceEnumerator, eRender};
-use windows::Win32::System::Com::StructuredStorage::{PROPVARIANT, PropVariantClear, PropVariantToStringAlloc};
+use windows::Win32::Foundation::{
+  CloseHandle, E_NOTFOUND, E_PENDING, PROPERTYKEY, RPC_E_CALL_REJECTED, RPC_E_SERVERCALL_RETRYLATER, S_OK
+};
+use windows::Win32::Media::Audio::Endpoints::{
+  ENDPOINT_HARDWARE_SUPPORT_METER, ENDPOINT_HARDWARE_SUPPORT_MUTE, ENDPOINT_HARDWARE_SUPPORT_VOLUME, IAudioEndpointVolume,
+  IAudioMeterInformation
+};
+use windows::Win32::Media::Audio::{
+  AUDCLNT_E_DEVICE_INVALIDATED, AUDCLNT_E_DEVICE_IN_USE, AUDCLNT_E_RESOURCES_INVALIDATED, AUDCLNT_E_SERVICE_NOT_RUNNING,
+  AudioSessionStateActive, AudioSessionStateExpired, DEVICE_STATEMASK_ALL, DEVICE_STATE_ACTIVE, DEVICE_STATE_DISABLED,
+  IAudioSessionControl, IAudioSessionControl2, IAudioSessionManager2, IMMDevice, IMMDeviceCollection,
+  IMMDeviceEnumerator, IMMEndpoint, ISimpleAudioVolume, MMDeviceEnumerator, PKEY_AudioEndpoint_FormFactor,
+  PKEY_AudioEndpoint_JackSubType, eAll, eCapture, eCommunications, eConsole, eMultimedia, eRender
+};
+use windows::Win32::System::Com::StructuredStorage::{PROPVARIANT, PropVariantClear, PropVariantToStringAlloc, PropVariantToUInt32};
 use windows::Win32::System::Com::{CLSCTX_ALL, CLSCTX_INPROC_SERVER, CoCreateInstance, CoTaskMemFree, STGM_READ};
+use windows::Win32::System::Threading::{
+  OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION
+};
+use windows::Win32::UI::Shell::PropertiesSystem::IPropertyStore;
 
-use crate::audio::{AudioDevice, AudioDeviceEnumerator};
+use crate::audio::{
+  AudioDevice, AudioDeviceEnumerator, DataFlow, DeviceRole, DeviceState, EndpointInfo, FormFactor, HardwareSupport, SessionInfo,
+  VolumeRange, VolumeStepInfo
+};
+
+/// `PKEY_Device_InstanceId` from devpkey.h.
+const PKEY_DEVICE_INSTANCE_ID: PROPERTYKEY = PROPERTYKEY {
+  fmtid: GUID::from_u128(0x78c34fc8_104a_4aca_9ea4_524d52996e57),
+  pid: 256
+};
+
+/// `PKEY_DeviceClass_IconPath` from devpkey.h, which the endpoint property store also carries.
+const PKEY_DEVICE_CLASS_ICON_PATH: PROPERTYKEY = PROPERTYKEY {
+  fmtid: GUID::from_u128(0x259abffc_50a7_47ce_af08_68c9a7d73366),
+  pid: 12
+};
+
+/// Number of attempts made for a COM call that keeps failing transiently.
+const RETRY_ATTEMPTS: u32 = 3;
+/// Delay before the first retry. Doubles with every further attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// How a failed COM call should be handled.
+#[derive(Debug, PartialEq)]
+enum FailureKind {
+  /// Likely to succeed if retried shortly, e.g. while a driver is resetting or a device is switching.
+  Transient,
+  /// The object the call was made on is no longer usable and has to be recreated.
+  Fatal,
+  Other
+}
+
+/// `AUDCLNT_E_RESOURCES_INVALIDATED` is fatal rather than transient: the interface it came from stays
+/// unusable no matter how often the call is retried.
+fn classify_failure(code: HRESULT) -> FailureKind {
+  let fatal = [AUDCLNT_E_DEVICE_INVALIDATED, AUDCLNT_E_RESOURCES_INVALIDATED, AUDCLNT_E_SERVICE_NOT_RUNNING, E_NOTFOUND];
+  if fatal.contains(&code) {
+    FailureKind::Fatal
+  } else if [RPC_E_CALL_REJECTED, RPC_E_SERVERCALL_RETRYLATER, E_PENDING, AUDCLNT_E_DEVICE_IN_USE].contains(&code) {
+    FailureKind::Transient
+  } else {
+    FailureKind::Other
+  }
+}
+
+/// Runs `call`, retrying with exponential backoff while it fails with a transient error.
+fn retry_transient<T>(mut call: impl FnMut() -> windows::core::Result<T>) -> windows::core::Result<T> {
+  let mut attempt = 1;
+  loop {
+    match call() {
+      Err(err) if attempt < RETRY_ATTEMPTS && classify_failure(err.code()) == FailureKind::Transient => {
+        std::thread::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1));
+        attempt += 1;
+      },
+      result => return result
+    }
+  }
+}
+
+/// A string allocated by COM, freed with `CoTaskMemFree` when dropped.
+///
+/// Device IDs are read on every enumeration, so any early return that skipped the free would leak on
+/// every poll. Wrapping the buffer as soon as COM hands it over keeps repeated enumeration from growing
+/// memory regardless of which path returns.
+struct CoTaskMemString(PWSTR);
+
+impl CoTaskMemString {
+  fn to_owned_string(&self) -> Result<String, String> {
+    unsafe { self.0.to_string().map_err(|err| format!("Invalid UTF-16 string: {err}")) }
+  }
+}
+
+impl Drop for CoTaskMemString {
+  fn drop(&mut self) {
+    unsafe { CoTaskMemFree(Some(self.0.0 as _)) };
+  }
+}
 
 pub struct WasapiAudioDevice {
   mm_device: IMMDevice,
-  volume_interface: IAudioEndpointVolume
+  /// Opened once and reused for every property read. Replaced if it gets invalidated.
+  property_store: RefCell<IPropertyStore>,
+  /// Only available while the device is active; the interfaces can't be activated on disabled devices.
+  volume_interface: Option<IAudioEndpointVolume>,
+  meter_interface: Option<IAudioMeterInformation>,
+  form_factor: FormFactor,
+  data_flow: DataFlow,
+  connector: Option<String>,
+  icon_path: Option<String>,
+  event_context: GUID
 }
 
 impl WasapiAudioDevice {
-  pub fn from_mm_device(mm_device: IMMDevice) -> Result<Self, String> {
-    let volume_interface = unsafe { 
-      mm_device
-        .Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None)
-        .map_err(|err| format!("Couldn't activate IAudioEndpointVolume: {err}"))?
+  pub fn from_mm_device(mm_device: IMMDevice, event_context: GUID) -> Result<Self, String> {
+    let state = unsafe { mm_device.GetState() }.map_err(|err| format!("Couldn't get device state: {err}"))?;
+    let (volume_interface, meter_interface) = match state == DEVICE_STATE_ACTIVE {
+      true => {
+        let volume_interface = retry_transient(|| unsafe { mm_device.Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None) })
+          .map_err(|err| format!("Couldn't activate IAudioEndpointVolume: {err}"))?;
+        let meter_interface = retry_transient(|| unsafe { mm_device.Activate::<IAudioMeterInformation>(CLSCTX_ALL, None) })
+          .map_err(|err| format!("Couldn't activate IAudioMeterInformation: {err}"))?;
+        (Some(volume_interface), Some(meter_interface))
+      },
+      false => (None, None)
     };
-    Ok(WasapiAudioDevice {
+    let property_store = open_property_store(&mm_device)?;
+    let mut device = WasapiAudioDevice {
+      property_store: RefCell::new(property_store),
       mm_device,
-      volume_interface
-    })
+      volume_interface,
+      meter_interface,
+      form_factor: FormFactor::Unknown,
+      data_flow: DataFlow::Render,
+      connector: None,
+      icon_path: None,
+      event_context
+    };
+    device.form_factor = unsafe { device.get_u32_property(&PKEY_AudioEndpoint_FormFactor) }
+      .map(form_factor_from_u32)
+      .unwrap_or(FormFactor::Unknown);
+    device.data_flow = unsafe { device.mm_device.cast::<IMMEndpoint>().and_then(|endpoint| endpoint.GetDataFlow()) }
+      .map(|data_flow| match data_flow == eCapture {
+        true => DataFlow::Capture,
+        false => DataFlow::Render
+      })
+      .unwrap_or(DataFlow::Render);
+    device.connector = unsafe { device.get_string_property(&PKEY_AudioEndpoint_JackSubType) }
+      .ok()
+      .filter(|jack_sub_type| !jack_sub_type.is_empty())
+      .map(|jack_sub_type| connector_from_jack_sub_type(&jack_sub_type));
+    device.icon_path = unsafe { device.get_string_property(&PKEY_DEVICE_CLASS_ICON_PATH) }
+      .ok()
+      .filter(|icon_path| !icon_path.is_empty());
+    Ok(device)
+  }
+
+  /// The device's sessions that haven't expired. Only available while the device is active.
+  fn session_controls(&self) -> Result<Vec<IAudioSessionControl>, String> {
+    self.volume_interface()?;
+    unsafe {
+      let session_manager = retry_transient(|| self.mm_device.Activate::<IAudioSessionManager2>(CLSCTX_ALL, None))
+        .map_err(|err| format!("Couldn't activate IAudioSessionManager2: {err}"))?;
+      let sessions = session_manager.GetSessionEnumerator()
+        .map_err(|err| format!("Couldn't enumerate audio sessions: {err}"))?;
+      let count = sessions.GetCount().map_err(|err| format!("Couldn't get audio session count: {err}"))?;
+
+      let mut controls = Vec::new();
+      for index in 0..count {
+        let control = sessions.GetSession(index).map_err(|err| format!("Couldn't get audio session: {err}"))?;
+        if !control.GetState().is_ok_and(|state| state == AudioSessionStateExpired) {
+          controls.push(control);
+        }
+      }
+      Ok(controls)
+    }
+  }
+
+  fn volume_interface(&self) -> Result<&IAudioEndpointVolume, String> {
+    self.volume_interface.as_ref().ok_or_else(|| "Device is not active".to_string())
+  }
+
+  fn meter_interface(&self) -> Result<&IAudioMeterInformation, String> {
+    self.meter_interface.as_ref().ok_or_else(|| "Device is not active".to_string())
   }
 
   unsafe fn get_property(&self, pkey: &PROPERTYKEY) -> Result<PROPVARIANT, String> {
-    let store = self.mm_device
-      .OpenPropertyStore(STGM_READ)
-      .map_err(|err| format!("Couldn't open device property store: {err}"))?;
-    let prop = store
-      .GetValue(pkey)
-      .map_err(|err| format!("Couldn't get property value: {err}"))?;
-    Ok(prop)
+    let result = self.property_store.borrow().GetValue(pkey);
+    match result {
+      Err(err) if classify_failure(err.code()) == FailureKind::Fatal => {
+        // The store went stale, e.g. because the driver was reloaded. Reopen it once.
+        let store = open_property_store(&self.mm_device)?;
+        let prop = store.GetValue(pkey).map_err(|err| format!("Couldn't get property value: {err}"))?;
+        *self.property_store.borrow_mut() = store;
+        Ok(prop)
+      },
+      result => result.map_err(|err| format!("Couldn't get property value: {err}"))
+    }
   }
 
   unsafe fn get_string_property(&self, pkey: &PROPERTYKEY) -> Result<String, String> {
+    propvariant_into_string(self.get_property(pkey)?)
+  }
+
+  unsafe fn get_u32_property(&self, pkey: &PROPERTYKEY) -> Result<u32, String> {
     let mut prop = self.get_property(pkey)?;
-    let propstr_id = PropVariantToStringAlloc(&prop)
-      .map_err(|err| format!("Couldn't alloc memory for PropVariant to string conversion: {err}"))?;
-    let propstr = prop.to_string();
+    let value = PropVariantToUInt32(&prop)
+      .map_err(|err| format!("Couldn't convert PropVariant to u32: {err}"));
 
-    CoTaskMemFree(Some(propstr_id.0 as _));
     PropVariantClear(&mut prop).map_err(|err| format!("Couldn't clear PropVariant: {err}"))?;
 
-    Ok(propstr)
+    value
+  }
+}
+
+unsafe fn propvariant_into_string(mut prop: PROPVARIANT) -> Result<String, String> {
+  let propstr = PropVariantToStringAlloc(&prop)
+    .map(CoTaskMemString)
+    .map_err(|err| format!("Couldn't alloc memory for PropVariant to string conversion: {err}"))?;
+  let value = propstr.to_owned_string();
+
+  PropVariantClear(&mut prop).map_err(|err| format!("Couldn't clear PropVariant: {err}"))?;
+
+  value
+}
+
+fn open_property_store(mm_device: &IMMDevice) -> Result<IPropertyStore, String> {
+  unsafe { mm_device.OpenPropertyStore(STGM_READ) }.map_err(|err| format!("Couldn't open device property store: {err}"))
+}
+
+/// Maps an `EndpointFormFactor` value to a `FormFactor`.
+fn form_factor_from_u32(value: u32) -> FormFactor {
+  match value {
+    0 => FormFactor::RemoteNetworkDevice,
+    1 => FormFactor::Speakers,
+    2 => FormFactor::LineLevel,
+    3 => FormFactor::Headphones,
+    4 => FormFactor::Microphone,
+    5 => FormFactor::Headset,
+    6 => FormFactor::Handset,
+    7 => FormFactor::UnknownDigitalPassthrough,
+    8 => FormFactor::Spdif,
+    9 => FormFactor::DigitalAudioDisplayDevice,
+    _ => FormFactor::Unknown
+  }
+}
+
+/// Maps a `PKEY_AudioEndpoint_JackSubType` value, which is a `KSNODETYPE_*` GUID, to a readable connector
+/// name. GUIDs that aren't recognized are returned as is, which still tells endpoints apart.
+fn connector_from_jack_sub_type(jack_sub_type: &str) -> String {
+  let connector = match jack_sub_type.trim_matches(['{', '}']).to_uppercase().as_str() {
+    "DFF21CE1-F70F-11D0-B917-00A0C9223196" => "Speaker",
+    "DFF21CE2-F70F-11D0-B917-00A0C9223196" => "Headphones",
+    "DFF21CE4-F70F-11D0-B917-00A0C9223196" => "Desktop speaker",
+    "DFF21CE5-F70F-11D0-B917-00A0C9223196" => "Room speaker",
+    "DFF21CE6-F70F-11D0-B917-00A0C9223196" => "Communication speaker",
+    "DFF21BE1-F70F-11D0-B917-00A0C9223196" => "Microphone",
+    "DFF21BE2-F70F-11D0-B917-00A0C9223196" => "Desktop microphone",
+    "DFF21DE2-F70F-11D0-B917-00A0C9223196" => "Headset",
+    "DFF21FE3-F70F-11D0-B917-00A0C9223196" => "Line",
+    "DFF21FE5-F70F-11D0-B917-00A0C9223196" => "S/PDIF",
+    "D1B9CC2A-F519-417F-91C9-55FA65481001" => "HDMI",
+    "E47E4031-3EA6-418D-8F9B-B73843CCBA97" => "DisplayPort",
+    _ => return jack_sub_type.to_string()
+  };
+  connector.to_string()
+}
+
+/// Maps a `DEVICE_STATE_*` value to a `DeviceState`.
+fn device_state_from_u32(value: u32) -> DeviceState {
+  match value {
+    1 => DeviceState::Active,
+    2 => DeviceState::Disabled,
+    4 => DeviceState::NotPresent,
+    _ => DeviceState::Unplugged
+  }
+}
+
+/// Reads what the volume mixer shows about a session.
+fn session_info(control: &IAudioSessionControl) -> Result<SessionInfo, String> {
+  unsafe {
+    let control2 = control.cast::<IAudioSessionControl2>()
+      .map_err(|err| format!("Couldn't get IAudioSessionControl2: {err}"))?;
+    let simple_volume = control.cast::<ISimpleAudioVolume>()
+      .map_err(|err| format!("Couldn't get ISimpleAudioVolume: {err}"))?;
+
+    let instance_id = control2.GetSessionInstanceIdentifier()
+      .map(CoTaskMemString)
+      .map_err(|err| format!("Couldn't get session instance ID: {err}"))?
+      .to_owned_string()?;
+    let is_system_sounds = control2.IsSystemSoundsSession() == S_OK;
+    // Sessions spanning several processes report a success code other than S_OK along with a meaningless ID.
+    let process_id = match is_system_sounds {
+      true => None,
+      false => control2.GetProcessId().ok().filter(|process_id| *process_id != 0)
+    };
+    let process_name = process_id.and_then(process_name);
+    let display_name = control.GetDisplayName()
+      .map(CoTaskMemString)
+      .ok()
+      .and_then(|display_name| display_name.to_owned_string().ok())
+      .filter(|display_name| !display_name.is_empty() && !display_name.starts_with('@'));
+    let display_name = match (display_name, &process_name, is_system_sounds) {
+      (Some(display_name), _, _) => display_name,
+      (None, _, true) => "System sounds".to_string(),
+      (None, Some(process_name), false) => process_name.clone(),
+      (None, None, false) => "Unknown app".to_string()
+    };
+
+    Ok(SessionInfo {
+      instance_id,
+      process_id,
+      process_name,
+      display_name,
+      volume: simple_volume.GetMasterVolume().map_err(|err| format!("Couldn't get session volume: {err}"))?,
+      is_muted: simple_volume.GetMute().map_err(|err| format!("Couldn't get session mute state: {err}"))?.as_bool(),
+      is_system_sounds,
+      active: control.GetState().is_ok_and(|state| state == AudioSessionStateActive),
+      peak: control.cast::<IAudioMeterInformation>()
+        .and_then(|meter| meter.GetPeakValue())
+        .unwrap_or(0.0)
+    })
+  }
+}
+
+/// The file name of the process's executable, or `None` if the process can't be queried. Opening
+/// elevated or protected processes is denied unless we're elevated too, which is expected.
+fn process_name(process_id: u32) -> Option<String> {
+  unsafe {
+    let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id).ok()?;
+    let mut buffer = [0u16; 1024];
+    let mut len = buffer.len() as u32;
+    let result = QueryFullProcessImageNameW(process, PROCESS_NAME_WIN32, PWSTR(buffer.as_mut_ptr()), &mut len);
+    let _ = CloseHandle(process);
+    result.ok()?;
+
+    let path = String::from_utf16_lossy(&buffer[..len as usize]);
+    path.rsplit(['\\', '/']).next().map(str::to_string)
   }
 }
 
+fn mm_device_id(mm_device: &IMMDevice) -> Result<String, String> {
+  let id = unsafe { mm_device.GetId().map(CoTaskMemString).map_err(|err| format!("Couldn't get device ID: {err}"))? };
+  id.to_owned_string().map_err(|err| format!("Couldn't get device ID: {err}"))
+}
+
 impl AudioDevice for WasapiAudioDevice {
   fn get_id(&self) -> Result<String, String> {
-    let pwstr = unsafe { self.mm_device.GetId().map_err(|err| format!("Couldn't get device ID: {err}"))? };
-    let id = unsafe { pwstr.to_string().map_err(|err| format!("Couldn't get device ID: {err}"))? };
-    unsafe { CoTaskMemFree(Some(pwstr.0 as _)) };
-    Ok(id)
+    mm_device_id(&self.mm_device)
   }
 
   fn get_name(&self) -> Result<String, String> {
@@ -62,7 +359,7 @@ impl AudioDevice for WasapiAudioDevice {
 
   fn get_volume(&self) -> Result<f32, String> {
     unsafe {
-      self.volume_interface
+      self.volume_interface()?
         .GetMasterVolumeLevelScalar()
         .map_err(|err| format!("Couldn't get device volume: {err}"))
     }
@@ -70,11 +367,147 @@ impl AudioDevice for WasapiAudioDevice {
 
   fn set_volume(&mut self, volume: f32) -> Result<(), String> {
     unsafe {
-      self.volume_interface
-        .SetMasterVolumeLevelScalar(volume, std::ptr::null())
+      self.volume_interface()?
+        .SetMasterVolumeLevelScalar(volume, &self.event_context)
         .map_err(|err| format!("Couldn't set device volume: {err}"))
     }
   }
+
+  fn get_mute(&self) -> Result<bool, String> {
+    unsafe {
+      self.volume_interface()?
+        .GetMute()
+        .map(|muted| muted.as_bool())
+        .map_err(|err| format!("Couldn't get device mute state: {err}"))
+    }
+  }
+
+  fn set_mute(&mut self, muted: bool) -> Result<(), String> {
+    unsafe {
+      self.volume_interface()?
+        .SetMute(muted, &self.event_context)
+        .map_err(|err| format!("Couldn't set device mute state: {err}"))
+    }
+  }
+
+  fn query_hardware_support(&self) -> Result<HardwareSupport, String> {
+    let mask = unsafe {
+      self.volume_interface()?
+        .QueryHardwareSupport()
+        .map_err(|err| format!("Couldn't query device hardware support: {err}"))?
+    };
+    Ok(HardwareSupport {
+      volume: mask & ENDPOINT_HARDWARE_SUPPORT_VOLUME != 0,
+      mute: mask & ENDPOINT_HARDWARE_SUPPORT_MUTE != 0,
+      meter: mask & ENDPOINT_HARDWARE_SUPPORT_METER != 0
+    })
+  }
+
+  fn get_peak_value(&self) -> Result<f32, String> {
+    unsafe {
+      self.meter_interface()?
+        .GetPeakValue()
+        .map_err(|err| format!("Couldn't get device peak value: {err}"))
+    }
+  }
+
+  fn get_form_factor(&self) -> FormFactor {
+    self.form_factor
+  }
+
+  fn get_data_flow(&self) -> DataFlow {
+    self.data_flow
+  }
+
+  fn is_invalidated(&self) -> bool {
+    let result = unsafe {
+      match &self.volume_interface {
+        Some(volume_interface) => volume_interface.GetMute().map(|_| ()),
+        None => self.mm_device.GetState().map(|_| ())
+      }
+    };
+    result.is_err_and(|err| classify_failure(err.code()) == FailureKind::Fatal)
+  }
+
+  fn get_connector(&self) -> Option<String> {
+    self.connector.clone()
+  }
+
+  fn get_icon_path(&self) -> Option<String> {
+    self.icon_path.clone()
+  }
+
+  fn get_instance_id(&self) -> Result<String, String> {
+    unsafe { self.get_string_property(&PKEY_DEVICE_INSTANCE_ID) }
+  }
+
+  fn get_state(&self) -> Result<DeviceState, String> {
+    let state = unsafe {
+      self.mm_device
+        .GetState()
+        .map_err(|err| format!("Couldn't get device state: {err}"))?
+    };
+    Ok(device_state_from_u32(state.0))
+  }
+
+  fn get_channel_count(&self) -> Result<u32, String> {
+    unsafe {
+      self.volume_interface()?
+        .GetChannelCount()
+        .map_err(|err| format!("Couldn't get device channel count: {err}"))
+    }
+  }
+
+  fn get_channel_volume(&self, channel: u32) -> Result<f32, String> {
+    unsafe {
+      self.volume_interface()?
+        .GetChannelVolumeLevelScalar(channel)
+        .map_err(|err| format!("Couldn't get volume of channel {channel}: {err}"))
+    }
+  }
+
+  fn get_volume_range(&self) -> Result<Option<VolumeRange>, String> {
+    let (mut min_db, mut max_db, mut increment_db) = (0.0f32, 0.0f32, 0.0f32);
+    unsafe {
+      self.volume_interface()?
+        .GetVolumeRange(&mut min_db, &mut max_db, &mut increment_db)
+        .map_err(|err| format!("Couldn't get device volume range: {err}"))?;
+    }
+    Ok((max_db > min_db).then_some(VolumeRange { min_db, max_db, increment_db }))
+  }
+
+  fn get_volume_step_info(&self) -> Result<Option<VolumeStepInfo>, String> {
+    let (mut step, mut step_count) = (0u32, 0u32);
+    unsafe {
+      self.volume_interface()?
+        .GetVolumeStepInfo(&mut step, &mut step_count)
+        .map_err(|err| format!("Couldn't get device volume step info: {err}"))?;
+    }
+    Ok((step_count > 1).then_some(VolumeStepInfo { step, step_count }))
+  }
+
+  fn get_sessions(&self) -> Result<Vec<SessionInfo>, String> {
+    self.session_controls()?.iter().map(session_info).collect()
+  }
+
+  fn set_session_volume(&mut self, instance_id: &str, volume: f32) -> Result<(), String> {
+    for control in self.session_controls()? {
+      let control2 = control.cast::<IAudioSessionControl2>()
+        .map_err(|err| format!("Couldn't get IAudioSessionControl2: {err}"))?;
+      let id = unsafe { control2.GetSessionInstanceIdentifier() }
+        .map(CoTaskMemString)
+        .map_err(|err| format!("Couldn't get session instance ID: {err}"))?;
+      if id.to_owned_string()? != instance_id {
+        continue;
+      }
+      return unsafe {
+        control.cast::<ISimpleAudioVolume>()
+          .and_then(|simple_volume| simple_volume.SetMasterVolume(volume, &self.event_context))
+          .map_err(|err| format!("Couldn't set session volume: {err}"))
+      };
+    }
+    Err("Session no longer exists".to_string())
+  }
 }
 
 struct WasapiAudioDeviceCollection {
@@ -82,12 +515,12 @@ struct WasapiAudioDeviceCollection {
 }
 
 impl WasapiAudioDeviceCollection {
-  pub fn from_enumerator(enumerator: &IMMDeviceEnumerator) -> Result<Self, String> {
-    let mm_device_collection = unsafe { 
-      enumerator
-        .EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)
-        .map_err(|err| format!("Couldn't get active device collection: {err}"))?
+  pub fn from_enumerator(enumerator: &IMMDeviceEnumerator, include_disabled: bool) -> windows::core::Result<Self> {
+    let state_mask = match include_disabled {
+      true => DEVICE_STATE_ACTIVE | DEVICE_STATE_DISABLED,
+      false => DEVICE_STATE_ACTIVE
     };
+    let mm_device_collection = retry_transient(|| unsafe { enumerator.EnumAudioEndpoints(eAll, state_mask) })?;
     Ok(WasapiAudioDeviceCollection { mm_device_collection })
   }
 
@@ -99,64 +532,185 @@ impl WasapiAudioDeviceCollection {
     }
   }
 
-  pub fn get_device(&self, index: u32) -> Result<WasapiAudioDevice, String> {
-    let device = unsafe {
+  /// ID and state of the device at `index`, read without activating anything on it.
+  pub fn get_device_state(&self, index: u32) -> Result<(String, DeviceState), String> {
+    let mm_device = unsafe {
       self.mm_device_collection
         .Item(index)
         .map_err(|err| format!("Couldn't get device at index {index}: {err}"))?
     };
-    WasapiAudioDevice::from_mm_device(device)
+    let state = unsafe { mm_device.GetState() }.map_err(|err| format!("Couldn't get device state: {err}"))?;
+    Ok((mm_device_id(&mm_device)?, device_state_from_u32(state.0)))
   }
 }
 
-impl IntoIterator for WasapiAudioDeviceCollection {
-  type Item = WasapiAudioDevice;
-  type IntoIter = WasapiAudioDeviceCollectionIntoIter;
+pub struct WasapiAudioDeviceEnumerator {
+  mm_device_enumerator: IMMDeviceEnumerator,
+  event_context: GUID
+}
 
-  fn into_iter(self) -> Self::IntoIter {
-    WasapiAudioDeviceCollectionIntoIter {
-      collection: self,
-      index: 0
-    }
+impl AudioDeviceEnumerator<WasapiAudioDevice> for WasapiAudioDeviceEnumerator {
+  fn init(event_context: GUID) -> Result<Self, String> {
+    let mm_device_enumerator = unsafe {
+      CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_INPROC_SERVER)
+        .map_err(|err| format!("Couldn't create device enumerator instance: {err}"))?
+    };
+
+    Ok(WasapiAudioDeviceEnumerator { mm_device_enumerator, event_context })
   }
-}
 
-pub struct WasapiAudioDeviceCollectionIntoIter {
-  collection: WasapiAudioDeviceCollection,
-  index: u32
-}
+  fn get_device_states(&self, include_disabled: bool) -> Result<Vec<(String, DeviceState)>, String> {
+    let collection = WasapiAudioDeviceCollection::from_enumerator(&self.mm_device_enumerator, include_disabled)
+      .map_err(|err| format!("Couldn't get device collection: {err}"))?;
 
-impl Iterator for WasapiAudioDeviceCollectionIntoIter {
-  type Item = WasapiAudioDevice;
+    // A device that can't be read, e.g. because it was unplugged mid-enumeration, is left out.
+    Ok((0..collection.get_count()?)
+      .filter_map(|index| collection.get_device_state(index).ok())
+      .collect())
+  }
 
-  fn next(&mut self) -> Option<Self::Item> {
-    if self.index >= self.collection.get_count().ok()? {
-      return None;
-    }
+  fn get_device(&self, device_id: &str) -> Result<WasapiAudioDevice, String> {
+    let mm_device = unsafe { self.mm_device_enumerator.GetDevice(&HSTRING::from(device_id)) }
+      .map_err(|err| format!("Couldn't get device: {err}"))?;
+    WasapiAudioDevice::from_mm_device(mm_device, self.event_context)
+  }
 
-    let device = self.collection.get_device(self.index).ok()?;
-    self.index += 1;
-    Some(device)
+  fn get_default_device_id(&self, data_flow: DataFlow, role: DeviceRole) -> Result<Option<String>, String> {
+    let data_flow = match data_flow {
+      DataFlow::Render => eRender,
+      DataFlow::Capture => eCapture
+    };
+    let role = match role {
+      DeviceRole::Console => eConsole,
+      DeviceRole::Multimedia => eMultimedia,
+      DeviceRole::Communications => eCommunications
+    };
+    let mm_device = match unsafe { self.mm_device_enumerator.GetDefaultAudioEndpoint(data_flow, role) } {
+      Ok(mm_device) => mm_device,
+      Err(err) if err.code() == E_NOTFOUND => return Ok(None),
+      Err(err) => return Err(format!("Couldn't get default device: {err}"))
+    };
+    mm_device_id(&mm_device).map(Some)
   }
-}
 
-pub struct WasapiAudioDeviceEnumerator {
-  mm_device_enumerator: IMMDeviceEnumerator
+  fn get_endpoints(&self, include_inactive: bool) -> Result<Vec<EndpointInfo>, String> {
+    let state_mask = match include_inactive {
+      true => DEVICE_STATEMASK_ALL,
+      false => DEVICE_STATE_ACTIVE
+    };
+    let collection = retry_transient(|| unsafe { self.mm_device_enumerator.EnumAudioEndpoints(eAll, state_mask) })
+      .map_err(|err| format!("Couldn't get device collection: {err}"))?;
+    let count = unsafe { collection.GetCount() }.map_err(|err| format!("Couldn't get device collection count: {err}"))?;
+
+    // As with `get_device_states`, an endpoint that can't be read is left out.
+    Ok((0..count)
+      .filter_map(|index| unsafe { collection.Item(index) }.ok())
+      .filter_map(|mm_device| endpoint_info(&mm_device).ok())
+      .collect())
+  }
 }
 
-impl AudioDeviceEnumerator<WasapiAudioDevice> for WasapiAudioDeviceEnumerator {
-  fn init() -> Result<Self, String> {
-    let mm_device_enumerator = unsafe {
-      CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_INPROC_SERVER)
-        .map_err(|err| format!("Couldn't create device enumerator instance: {err}"))?
+/// Reads an endpoint without activating anything on it.
+fn endpoint_info(mm_device: &IMMDevice) -> Result<EndpointInfo, String> {
+  unsafe {
+    let state = mm_device.GetState().map_err(|err| format!("Couldn't get device state: {err}"))?;
+    let data_flow = match mm_device.cast::<IMMEndpoint>().and_then(|endpoint| endpoint.GetDataFlow()) {
+      Ok(data_flow) if data_flow == eCapture => DataFlow::Capture,
+      _ => DataFlow::Render
     };
+    let name = open_property_store(mm_device)
+      .and_then(|store| store.GetValue(&PKEY_DeviceInterface_FriendlyName).map_err(|err| format!("{err}")))
+      .and_then(|prop| propvariant_into_string(prop))
+      .ok();
+    Ok(EndpointInfo {
+      id: mm_device_id(mm_device)?,
+      name,
+      data_flow,
+      state: device_state_from_u32(state.0),
+      is_default: false,
+      is_default_comms: false,
+      listed: false
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use criterion::Criterion;
+  use windows::Win32::Foundation::E_FAIL;
+  use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
 
-    Ok(WasapiAudioDeviceEnumerator { mm_device_enumerator })
+  #[test]
+  fn classify_failure_sorts_hresults() {
+    assert_eq!(classify_failure(AUDCLNT_E_DEVICE_INVALIDATED), FailureKind::Fatal);
+    assert_eq!(classify_failure(AUDCLNT_E_RESOURCES_INVALIDATED), FailureKind::Fatal);
+    assert_eq!(classify_failure(E_NOTFOUND), FailureKind::Fatal);
+    assert_eq!(classify_failure(RPC_E_CALL_REJECTED), FailureKind::Transient);
+    assert_eq!(classify_failure(AUDCLNT_E_DEVICE_IN_USE), FailureKind::Transient);
+    assert_eq!(classify_failure(E_FAIL), FailureKind::Other);
   }
 
-  fn into_iter(&self) -> impl Iterator<Item = WasapiAudioDevice> {
-    WasapiAudioDeviceCollection::from_enumerator(&self.mm_device_enumerator)
-      .unwrap()
-      .into_iter()
+  #[test]
+  fn retry_transient_retries_until_success() {
+    let mut calls = 0;
+    let result = retry_transient(|| {
+      calls += 1;
+      match calls {
+        1 | 2 => Err(windows::core::Error::from_hresult(E_PENDING)),
+        _ => Ok(calls)
+      }
+    });
+
+    assert_eq!(result, Ok(3));
+  }
+
+  #[test]
+  fn retry_transient_gives_up_after_the_last_attempt() {
+    let mut calls = 0;
+    let result: windows::core::Result<()> = retry_transient(|| {
+      calls += 1;
+      Err(windows::core::Error::from_hresult(RPC_E_SERVERCALL_RETRYLATER))
+    });
+
+    assert_eq!(result.unwrap_err().code(), RPC_E_SERVERCALL_RETRYLATER);
+    assert_eq!(calls, RETRY_ATTEMPTS);
+  }
+
+  #[test]
+  fn retry_transient_doesnt_retry_other_failures() {
+    for code in [AUDCLNT_E_DEVICE_INVALIDATED, AUDCLNT_E_RESOURCES_INVALIDATED, E_FAIL] {
+      let mut calls = 0;
+      let result: windows::core::Result<()> = retry_transient(|| {
+        calls += 1;
+        Err(windows::core::Error::from_hresult(code))
+      });
+
+      assert_eq!(result.unwrap_err().code(), code);
+      assert_eq!(calls, 1);
+    }
+  }
+
+  /// The device calls of a poll cycle on this machine's real devices, for comparing with `device_cycle` on
+  /// the mocks. Opening a device is the cost that reusing cached devices saves. Only runs when asked:
+  /// `cargo test --release wasapi_device_cycle -- --ignored --nocapture`.
+  #[test]
+  #[ignore]
+  fn wasapi_device_cycle() {
+    unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.ok().unwrap();
+    let enumerator = WasapiAudioDeviceEnumerator::init(GUID::zeroed()).unwrap();
+    let device_ids: Vec<_> = enumerator.get_device_states(false).unwrap().into_iter().map(|(id, _)| id).collect();
+    println!("{} active devices", device_ids.len());
+
+    let mut criterion = Criterion::default();
+    criterion.bench_function("wasapi: enumerate devices", |b| b.iter(|| enumerator.get_device_states(false).unwrap()));
+    criterion.bench_function("wasapi: open every device", |b| {
+      b.iter(|| device_ids.iter().map(|id| enumerator.get_device(id).unwrap()).collect::<Vec<_>>())
+    });
+    let devices: Vec<_> = device_ids.iter().map(|id| enumerator.get_device(id).unwrap()).collect();
+    criterion.bench_function("wasapi: read every open device", |b| {
+      b.iter(|| devices.iter().map(|device| (device.get_name(), device.get_volume())).collect::<Vec<_>>())
+    });
+    criterion.final_summary();
   }
 }
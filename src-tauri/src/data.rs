@@ -1,45 +1,331 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use notify::{EventKind, RecursiveMode, Watcher};
 use tauri::{Manager, AppHandle, path::BaseDirectory};
 
 use crate::audio::AudioDeviceConfig;
+use crate::errors::{emit_error, AppError, ErrorCode};
+use crate::logging::{log, LogLevel};
 
 const DEVICE_DATA_FILE: &str = "devices.json";
+/// The device data as it was before the last write, so a bad edit can be undone.
+const DEVICE_DATA_BACKUP_FILE: &str = "devices.bak.json";
+const WRITE_DEBOUNCE_MS: u64 = 250;
+const DATA_DIR_ARG: &str = "--data-dir";
+const DATA_DIR_ENV: &str = "VOLUME_LIMITER_DATA_DIR";
+
+/// Pending device data waiting to be written to disk.
+///
+/// Rapid config changes (e.g. dragging a slider) are coalesced into a single write once no new change
+/// has arrived for `WRITE_DEBOUNCE_MS`. The controller stays the source of truth for reads in the meantime.
+#[derive(Default)]
+pub struct DeviceDataWriter {
+  pending: Mutex<Option<AudioDeviceConfig>>,
+  generation: AtomicU64,
+  /// What the file was last known to contain, either because we wrote it or because we reloaded it.
+  /// Used to tell our own writes apart from external edits.
+  last_synced: Mutex<Option<String>>
+}
+
+/// The data directory given with `--data-dir <dir>` or the `VOLUME_LIMITER_DATA_DIR` environment variable,
+/// e.g. to keep the data next to a portable install. The flag takes precedence. Relative paths are
+/// relative to the executable, not the working directory, which for a portable install isn't predictable.
+pub fn data_dir_override() -> Option<PathBuf> {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let arg_dir = args.iter().enumerate().find_map(|(index, arg)| match arg.strip_prefix(DATA_DIR_ARG) {
+    Some("") => args.get(index + 1).cloned(),
+    Some(rest) => rest.strip_prefix('=').map(str::to_string),
+    None => None
+  });
+  let dir = arg_dir
+    .or_else(|| std::env::var(DATA_DIR_ENV).ok())
+    .filter(|dir| !dir.trim().is_empty())
+    .map(PathBuf::from)?;
+
+  match dir.is_relative() {
+    true => std::env::current_exe().ok()?.parent().map(|exe_dir| exe_dir.join(&dir)),
+    false => Some(dir)
+  }
+}
+
+/// Resolves a file in the data directory, which is the app data directory unless overridden with
+/// `data_dir_override`. Every file the app keeps goes through here.
+pub fn data_path(app_handle: &AppHandle, file_name: &str) -> tauri::Result<PathBuf> {
+  match data_dir_override() {
+    Some(dir) => Ok(dir.join(file_name)),
+    None => app_handle.path().resolve(file_name, BaseDirectory::AppData)
+  }
+}
+
+/// Creates the data directory if needed and makes sure it can be written to, so a bad `--data-dir` fails
+/// at startup with the path in the message instead of on the first save.
+fn ensure_data_dir_writable(data_dir: &std::path::Path) -> std::io::Result<()> {
+  let with_context = |action: &str, err: std::io::Error| {
+    std::io::Error::new(err.kind(), format!("Couldn't {action} data directory '{}': {err}", data_dir.display()))
+  };
+  std::fs::create_dir_all(data_dir).map_err(|err| with_context("create", err))?;
+  let probe_path = data_dir.join(".write-test");
+  std::fs::write(&probe_path, []).map_err(|err| with_context("write to", err))?;
+  let _ = std::fs::remove_file(probe_path);
+  Ok(())
+}
 
 pub fn init_device_data(app_handle: &AppHandle) -> tauri::Result<()> {
-  let devices_path = app_handle.path().resolve(DEVICE_DATA_FILE, BaseDirectory::AppData)?;
+  let devices_path = data_path(app_handle, DEVICE_DATA_FILE)?;
+  ensure_data_dir_writable(devices_path.parent().unwrap())?;
   if !devices_path.exists() {
-    std::fs::create_dir_all(devices_path.parent().unwrap())?;
     std::fs::write(&devices_path, serde_json::to_string_pretty(&AudioDeviceConfig::default())?)?;
   }
 
   let json_str = std::fs::read_to_string(&devices_path)?;
   if serde_json::from_str::<AudioDeviceConfig>(&json_str).is_err() {
-    std::fs::write(&devices_path, serde_json::to_string_pretty(&AudioDeviceConfig::default())?)?;
+    let backup_path = data_path(app_handle, DEVICE_DATA_BACKUP_FILE)?;
+    let backup = std::fs::read_to_string(&backup_path).ok()
+      .filter(|backup| serde_json::from_str::<AudioDeviceConfig>(backup).is_ok());
+    match backup {
+      Some(backup) => {
+        log(LogLevel::Warn, "Device data is corrupt, restored it from the backup");
+        std::fs::write(&devices_path, backup)?;
+      },
+      None => {
+        log(LogLevel::Warn, "Device data is corrupt and there's no usable backup, reset it to defaults");
+        // The app has been run before, so the welcome screen isn't shown again.
+        let data = AudioDeviceConfig { first_run: false, ..AudioDeviceConfig::default() };
+        std::fs::write(&devices_path, serde_json::to_string_pretty(&data)?)?;
+      }
+    }
   }
   Ok(())
 }
 
-pub fn write_device_data(app_handle: &AppHandle, data: AudioDeviceConfig) -> Result<(), String> {
-  let devices_path = app_handle
-    .path()
-    .resolve(DEVICE_DATA_FILE, BaseDirectory::AppData)
+/// Copies the device data file to the backup, unless it's missing or can't be parsed, so a corrupt file
+/// never replaces a good backup.
+fn backup_device_data(app_handle: &AppHandle) -> Result<(), String> {
+  let devices_path = data_path(app_handle, DEVICE_DATA_FILE)
     .map_err(|err| format!("{}", err))?;
+  let backup_path = data_path(app_handle, DEVICE_DATA_BACKUP_FILE)
+    .map_err(|err| format!("{}", err))?;
+  backup_file(&devices_path, &backup_path)
+}
+
+fn backup_file(devices_path: &Path, backup_path: &Path) -> Result<(), String> {
+  let json_str = match std::fs::read_to_string(devices_path) {
+    Ok(json_str) => json_str,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(err) => return Err(format!("{}", err))
+  };
+  if AudioDeviceConfig::from_json(&json_str).is_err() {
+    return Ok(());
+  }
+  std::fs::write(backup_path, json_str).map_err(|err| format!("Couldn't back up device data: {err}"))
+}
+
+/// Reads the backup, making sure it's usable before anything is replaced with it.
+fn read_backup(backup_path: &Path) -> Result<String, String> {
+  let backup = match std::fs::read_to_string(backup_path) {
+    Ok(backup) => backup,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err("There is no backup".to_string()),
+    Err(err) => return Err(format!("Couldn't read backup: {err}"))
+  };
+  parse_device_data(&backup).map_err(|err| format!("Backup is invalid: {err}"))?;
+  Ok(backup)
+}
+
+/// Replaces the device data file with the backup. Any write still waiting to happen is dropped, so it
+/// can't overwrite the restored file. The caller is expected to reload the device data afterwards.
+pub fn restore_device_data_backup(app_handle: &AppHandle) -> Result<(), String> {
+  let devices_path = data_path(app_handle, DEVICE_DATA_FILE)
+    .map_err(|err| format!("{}", err))?;
+  let backup_path = data_path(app_handle, DEVICE_DATA_BACKUP_FILE)
+    .map_err(|err| format!("{}", err))?;
+
+  let backup = read_backup(&backup_path)?;
+  cancel_device_data_write(app_handle);
+  std::fs::write(&devices_path, backup).map_err(|err| format!("Couldn't restore backup: {err}"))
+}
+
+pub fn write_device_data(app_handle: &AppHandle, mut data: AudioDeviceConfig) -> Result<(), String> {
+  let devices_path = data_path(app_handle, DEVICE_DATA_FILE)
+    .map_err(|err| format!("{}", err))?;
+
+  data.app_version = app_handle.package_info().version.to_string();
 
   let json_str = serde_json::to_string_pretty(&data)
     .map_err(|err| format!("{}", err))?;
 
-  std::fs::write(&devices_path, json_str)
+  let writer = app_handle.state::<DeviceDataWriter>();
+  let mut last_synced = writer.last_synced.lock().unwrap();
+  backup_device_data(app_handle)?;
+  std::fs::write(&devices_path, &json_str)
     .map_err(|err| format!("{}", err))?;
+  *last_synced = Some(json_str);
 
   Ok(())
 }
 
 pub fn read_device_data(app_handle: &AppHandle) -> Result<AudioDeviceConfig, String> {
-  let devices_path = app_handle.path().resolve(DEVICE_DATA_FILE, BaseDirectory::AppData)
+  let devices_path = data_path(app_handle, DEVICE_DATA_FILE)
     .map_err(|err| format!("{}", err))?;
 
   let json_str = std::fs::read_to_string(&devices_path)
     .map_err(|err| format!("{}", err))?;
   
-  serde_json::from_str(&json_str)
-    .map_err(|err| format!("{}", err))
+  let data = parse_device_data(&json_str)?;
+  *app_handle.state::<DeviceDataWriter>().last_synced.lock().unwrap() = Some(json_str);
+
+  let app_version = app_handle.package_info().version.to_string();
+  if data.app_version != app_version {
+    let written_by = match data.app_version.is_empty() {
+      true => "an unknown version",
+      false => data.app_version.as_str()
+    };
+    log(LogLevel::Info, format!("Device data was written by {written_by}, running {app_version}"));
+  }
+  Ok(data)
+}
+
+fn parse_device_data(json_str: &str) -> Result<AudioDeviceConfig, String> {
+  let mut data = AudioDeviceConfig::from_json(json_str)?;
+
+  for fix in data.validate_and_clamp() {
+    log(LogLevel::Warn, format!("Fixed invalid device data: {fix}"));
+  }
+  Ok(data)
+}
+
+/// Reads device data that was changed outside the app. Returns `None` if the file is missing, as it can
+/// briefly be while an editor saves it, or if it still contains what we last wrote or reloaded.
+pub fn read_changed_device_data(app_handle: &AppHandle) -> Result<Option<AudioDeviceConfig>, String> {
+  let devices_path = data_path(app_handle, DEVICE_DATA_FILE)
+    .map_err(|err| format!("{}", err))?;
+
+  let json_str = match std::fs::read_to_string(&devices_path) {
+    Ok(json_str) => json_str,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+    Err(err) => return Err(format!("{}", err))
+  };
+
+  let writer = app_handle.state::<DeviceDataWriter>();
+  let mut last_synced = writer.last_synced.lock().unwrap();
+  if last_synced.as_deref() == Some(json_str.as_str()) {
+    return Ok(None);
+  }
+
+  let data = parse_device_data(&json_str)?;
+  *last_synced = Some(json_str);
+  Ok(Some(data))
+}
+
+/// Calls `on_change` whenever the device data file is created or modified. The directory is watched
+/// rather than the file, because editors often save by replacing the file.
+pub fn watch_device_data(app_handle: &AppHandle, on_change: impl Fn() + Send + 'static) -> Result<(), String> {
+  let devices_path = data_path(app_handle, DEVICE_DATA_FILE)
+    .map_err(|err| format!("{}", err))?;
+
+  let watched_path = devices_path.clone();
+  let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+    let Ok(event) = event else {
+      return;
+    };
+    let is_write = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+    if is_write && event.paths.contains(&watched_path) {
+      on_change();
+    }
+  }).map_err(|err| format!("Couldn't create file watcher: {err}"))?;
+
+  watcher
+    .watch(devices_path.parent().unwrap(), RecursiveMode::NonRecursive)
+    .map_err(|err| format!("Couldn't watch device data: {err}"))?;
+
+  app_handle.manage(Mutex::new(watcher));
+  Ok(())
+}
+
+pub fn schedule_device_data_write(app_handle: &AppHandle, data: AudioDeviceConfig) {
+  let writer = app_handle.state::<DeviceDataWriter>();
+  *writer.pending.lock().unwrap() = Some(data);
+  let generation = writer.generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+  let app_handle = app_handle.clone();
+  tauri::async_runtime::spawn(async move {
+    tokio::time::sleep(tokio::time::Duration::from_millis(WRITE_DEBOUNCE_MS)).await;
+
+    let writer = app_handle.state::<DeviceDataWriter>();
+    if writer.generation.load(Ordering::SeqCst) != generation {
+      return;
+    }
+
+    if let Err(err) = flush_device_data(&app_handle) {
+      emit_error(&app_handle, AppError::new(ErrorCode::DataSave, format!("Couldn't save device data: {err}")));
+    }
+  });
+}
+
+/// Drops the pending write, if any, and stops its scheduled flush, e.g. because the file has been replaced
+/// with newer data that the write would overwrite.
+pub fn cancel_device_data_write(app_handle: &AppHandle) {
+  let writer = app_handle.state::<DeviceDataWriter>();
+  writer.generation.fetch_add(1, Ordering::SeqCst);
+  *writer.pending.lock().unwrap() = None;
+}
+
+pub fn flush_device_data(app_handle: &AppHandle) -> Result<(), String> {
+  let writer = app_handle.state::<DeviceDataWriter>();
+  let pending = writer.pending.lock().unwrap().take();
+  match pending {
+    Some(data) => write_device_data(app_handle, data),
+    None => Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A fresh directory for one test, with the device data and backup paths in it.
+  fn data_dir(test_name: &str) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("volume-limiter-{test_name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    (dir.join(DEVICE_DATA_FILE), dir.join(DEVICE_DATA_BACKUP_FILE))
+  }
+
+  fn config_json(global_max_volume: f32) -> String {
+    let mut config = AudioDeviceConfig::default();
+    config.render.global_max_volume = global_max_volume;
+    serde_json::to_string(&config).unwrap()
+  }
+
+  #[test]
+  fn backup_can_be_restored() {
+    let (devices_path, backup_path) = data_dir("restore");
+    std::fs::write(&devices_path, config_json(0.4)).unwrap();
+    backup_file(&devices_path, &backup_path).unwrap();
+    std::fs::write(&devices_path, config_json(0.9)).unwrap();
+
+    let backup = read_backup(&backup_path).unwrap();
+    assert_eq!(AudioDeviceConfig::from_json(&backup).unwrap().render.global_max_volume, 0.4);
+  }
+
+  #[test]
+  fn corrupt_data_doesnt_replace_the_backup() {
+    let (devices_path, backup_path) = data_dir("corrupt");
+    std::fs::write(&devices_path, config_json(0.4)).unwrap();
+    backup_file(&devices_path, &backup_path).unwrap();
+    std::fs::write(&devices_path, "{ not json").unwrap();
+    backup_file(&devices_path, &backup_path).unwrap();
+
+    let backup = read_backup(&backup_path).unwrap();
+    assert_eq!(AudioDeviceConfig::from_json(&backup).unwrap().render.global_max_volume, 0.4);
+  }
+
+  #[test]
+  fn missing_or_invalid_backups_are_rejected() {
+    let (_, backup_path) = data_dir("invalid");
+    assert_eq!(read_backup(&backup_path), Err("There is no backup".to_string()));
+
+    std::fs::write(&backup_path, "{ not json").unwrap();
+    assert!(read_backup(&backup_path).unwrap_err().starts_with("Backup is invalid"));
+  }
 }
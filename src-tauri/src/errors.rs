@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Repeats of an error within this window after it was emitted are suppressed.
+const ERROR_SUPPRESSION_WINDOW: Duration = Duration::from_secs(5);
+
+/// What failed, so the frontend can localize, filter or group errors without parsing their messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+  /// Enumerating devices failed.
+  DeviceUpdate,
+  /// A limit couldn't be applied to a device.
+  Enforcement,
+  Ducking,
+  /// The audio thread didn't respond.
+  AudioThread,
+  DataSave,
+  DataReload,
+  Tray,
+  Shortcut,
+  Notification,
+  Window
+}
+
+impl ErrorCode {
+  /// Whether the failing work is retried on its own, so the error may clear up without the user doing
+  /// anything and can be dismissed automatically.
+  fn recoverable(self) -> bool {
+    matches!(self, ErrorCode::DeviceUpdate | ErrorCode::Enforcement | ErrorCode::Ducking | ErrorCode::Tray)
+  }
+}
+
+/// An error to report to the frontend through `emit_error`.
+#[derive(Clone, Debug)]
+pub struct AppError {
+  pub code: ErrorCode,
+  pub message: String,
+  /// The device the error is about, if it's about one.
+  pub device_id: Option<String>
+}
+
+impl AppError {
+  pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+    AppError { code, message: message.into(), device_id: None }
+  }
+
+  pub fn for_device(code: ErrorCode, device_id: &str, message: impl Into<String>) -> Self {
+    AppError { code, message: message.into(), device_id: Some(device_id.to_string()) }
+  }
+}
+
+/// Payload of the `error` event. `count` is 1 for a newly seen error, and the number of suppressed
+/// repeats when a suppression window ends.
+#[derive(Clone, serde::Serialize)]
+pub struct AppErrorEvent {
+  pub code: ErrorCode,
+  pub message: String,
+  pub device_id: Option<String>,
+  pub recoverable: bool,
+  pub count: u32
+}
+
+impl AppErrorEvent {
+  fn new(error: AppError, count: u32) -> Self {
+    AppErrorEvent {
+      code: error.code,
+      message: error.message,
+      device_id: error.device_id,
+      recoverable: error.code.recoverable(),
+      count
+    }
+  }
+}
+
+struct SuppressedError {
+  error: AppError,
+  emitted_at: Instant,
+  suppressed: u32
+}
+
+/// Deduplicates error events by message, so a persistent failure in a fast periodic task doesn't
+/// flood the frontend while genuinely new errors still get through immediately.
+#[derive(Default)]
+pub struct ErrorThrottle {
+  errors: HashMap<String, SuppressedError>
+}
+
+impl ErrorThrottle {
+  /// Returns the event to emit for `error`, or `None` if the same error was emitted within the window.
+  fn record(&mut self, error: AppError, now: Instant) -> Option<AppErrorEvent> {
+    if let Some(suppressed_error) = self.errors.get_mut(&error.message) {
+      suppressed_error.suppressed += 1;
+      return None;
+    }
+
+    self.errors.insert(error.message.clone(), SuppressedError { error: error.clone(), emitted_at: now, suppressed: 0 });
+    Some(AppErrorEvent::new(error, 1))
+  }
+
+  /// Ends the suppression windows that have elapsed, returning a summary event for each error that
+  /// was repeated during its window.
+  fn flush_expired(&mut self, now: Instant) -> Vec<AppErrorEvent> {
+    let expired: Vec<_> = self.errors.iter()
+      .filter(|(_, error)| now.duration_since(error.emitted_at) >= ERROR_SUPPRESSION_WINDOW)
+      .map(|(message, _)| message.clone())
+      .collect();
+
+    expired.into_iter()
+      .filter_map(|message| {
+        let error = self.errors.remove(&message)?;
+        (error.suppressed > 0).then(|| AppErrorEvent::new(error.error, error.suppressed))
+      })
+      .collect()
+  }
+}
+
+/// Emits an `error` event through the app's `ErrorThrottle`.
+pub fn emit_error(app_handle: &AppHandle, error: AppError) {
+  let events = {
+    let throttle = app_handle.state::<Mutex<ErrorThrottle>>();
+    let mut throttle = throttle.lock().unwrap();
+    let now = Instant::now();
+    let mut events = throttle.flush_expired(now);
+    events.extend(throttle.record(error, now));
+    events
+  };
+
+  for event in events {
+    app_handle.emit("error", event).unwrap();
+  }
+}
+
+/// Emits summaries for errors whose suppression window has ended. Called periodically so the summary
+/// arrives even if the error stops occurring.
+pub fn flush_errors(app_handle: &AppHandle) {
+  let events = app_handle.state::<Mutex<ErrorThrottle>>().lock().unwrap().flush_expired(Instant::now());
+  for event in events {
+    app_handle.emit("error", event).unwrap();
+  }
+}
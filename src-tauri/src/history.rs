@@ -0,0 +1,42 @@
+use std::collections::VecDeque;
+
+use crate::audio::AudioDeviceConfig;
+
+/// Maximum number of snapshots kept for undo.
+const CONFIG_HISTORY_LIMIT: usize = 50;
+
+/// Bounded undo/redo stacks of config snapshots.
+#[derive(Default)]
+pub struct ConfigHistory {
+  undo: VecDeque<AudioDeviceConfig>,
+  redo: Vec<AudioDeviceConfig>
+}
+
+impl ConfigHistory {
+  /// Records the config as it was before a change. Any redo history is discarded.
+  pub fn push(&mut self, snapshot: AudioDeviceConfig) {
+    self.push_undo(snapshot);
+    self.redo.clear();
+  }
+
+  /// Returns the snapshot to restore for an undo, moving `current` onto the redo stack.
+  pub fn undo(&mut self, current: AudioDeviceConfig) -> Option<AudioDeviceConfig> {
+    let snapshot = self.undo.pop_back()?;
+    self.redo.push(current);
+    Some(snapshot)
+  }
+
+  /// Returns the snapshot to restore for a redo, moving `current` back onto the undo stack.
+  pub fn redo(&mut self, current: AudioDeviceConfig) -> Option<AudioDeviceConfig> {
+    let snapshot = self.redo.pop()?;
+    self.push_undo(current);
+    Some(snapshot)
+  }
+
+  fn push_undo(&mut self, snapshot: AudioDeviceConfig) {
+    self.undo.push_back(snapshot);
+    if self.undo.len() > CONFIG_HISTORY_LIMIT {
+      self.undo.pop_front();
+    }
+  }
+}
@@ -1,139 +1,1393 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
-use tauri::menu::{Menu, MenuItem};
-use tauri::tray::{MouseButton, TrayIconBuilder, TrayIconEvent};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tauri::{Builder, Emitter, Manager, State, WindowEvent};
+use tokio::time::MissedTickBehavior;
+use windows::core::GUID;
 
-use crate::audio::AudioDeviceInfo;
-use crate::data::{init_device_data, read_device_data, write_device_data};
+use crate::about::{read_app_info, AppInfo};
+use crate::audio::{
+  display_to_scalar, percent_to_volume, scalar_to_display, validate_volume, AudioController, AudioDeviceConfig,
+  AudioDeviceInfo, AudioThread, CloseBehavior, ComApartment, DataFlow, DeviceApplyResult, DeviceChanges, DeviceRole,
+  EndpointInfo, Enforcement, EnforcementAction, ExceedPolicy, FormFactor, LimitMode, MergeStrategy, PartialConfig,
+  SessionInfo, ShortcutConfig, SimResult, SortOrder, VolumeChange, VolumeCurve
+};
+use crate::data::{
+  cancel_device_data_write, data_dir_override, flush_device_data, init_device_data, read_changed_device_data,
+  read_device_data, restore_device_data_backup, schedule_device_data_write, watch_device_data, DeviceDataWriter
+};
+use crate::errors::{emit_error, flush_errors, AppError, ErrorCode, ErrorThrottle};
+use crate::history::ConfigHistory;
+use crate::logging::{log, LogLevel};
+use crate::notifications::{notify_enforcements, NotificationThrottle};
+use crate::profile::{read_profile_file, write_profile_file};
+use crate::shortcuts::{handle_shortcut, parse_shortcut, sync_shortcuts, ShortcutBindings};
+use crate::stats::{DeviceStats, EnforcementRecord, EnforcementStats};
+use crate::tray::{create_tray, show_main_window, update_tray, update_tray_devices};
+use crate::window::{restore_window_geometry, save_window_geometry};
 
+mod about;
 mod audio;
 mod data;
+mod errors;
+mod history;
+mod logging;
+mod notifications;
+mod profile;
+mod shortcuts;
+mod stats;
+mod tray;
+mod window;
+
+/// Runs a config change on the audio thread, then records the previous config for undo, persists the
+/// new config, refreshes the tray and emits `devices-updated` and `config-changed`. None of that happens if
+/// the change leaves the config as it was.
+async fn update_config<R: Send + 'static>(
+  app_handle: &tauri::AppHandle,
+  audio: &AudioThread,
+  update: impl FnOnce(&mut AudioController) -> Result<R, String> + Send + 'static
+) -> Result<R, String> {
+  let revision_handle = app_handle.clone();
+  let (result, changed) = audio.call(move |controller| {
+    let (result, previous_config) = controller.change_config(update)?;
+    let Some(previous_config) = previous_config else {
+      return Ok::<_, String>((result, None));
+    };
+    bump_config_revision(&revision_handle);
+    Ok((result, Some((controller.get_devices(), previous_config, controller.get_config()))))
+  }).await??;
+  // Setting a value to what it already is, e.g. picking the current tray preset, neither touches the
+  // disk nor notifies anyone.
+  let Some((devices, previous_config, config)) = changed else {
+    return Ok(result);
+  };
+
+  app_handle.state::<Mutex<AppState>>().lock().unwrap().history.push(previous_config);
+  update_tray(app_handle, &config, &devices)?;
+  sync_shortcuts(app_handle, &config.shortcuts);
+  schedule_device_data_write(app_handle, config.clone());
+  app_handle.emit("devices-updated", DevicesUpdatedEvent { devices, changes: None }).map_err(|err| format!("{err}"))?;
+  app_handle.emit("config-changed", config).map_err(|err| format!("{err}"))?;
+  Ok(result)
+}
+
+#[tauri::command]
+async fn set_device_max_volume(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String, volume: f32) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.set_device_max_volume(&device_id, volume)).await
+}
+
+/// Caps whichever device is the default output right now.
+#[tauri::command]
+async fn set_default_device_max_volume(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, volume: f32) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.limit_default_device(volume).map(|_| ())).await
+}
+
+/// Like `set_default_device_max_volume`, but returns the device that got the cap.
+#[tauri::command]
+async fn limit_default_device(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, volume: f32) -> Result<AudioDeviceInfo, String> {
+  update_config(&app_handle, &audio, move |controller| controller.limit_default_device(volume)).await
+}
+
+#[tauri::command]
+async fn set_device_max_volumes(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, max_volumes: HashMap<String, f32>) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.set_device_max_volumes(max_volumes)).await
+}
 
 #[tauri::command]
-fn set_device_max_volume(app_handle: tauri::AppHandle, device_id: &str, volume: f32) -> Result<(), String> {
-  let state = app_handle.state::<Mutex<AppState>>();
-  let controller = &mut state.lock().unwrap().audio_controller;
+async fn set_global_max_volume(
+  app_handle: tauri::AppHandle,
+  audio: State<'_, AudioThread>,
+  volume: f32,
+  data_flow: Option<DataFlow>
+) -> Result<Vec<DeviceApplyResult>, String> {
+  let data_flow = data_flow.unwrap_or_default();
+  update_config(&app_handle, &audio, move |controller| controller.set_global_max_volume(data_flow, volume)).await
+}
+
+#[tauri::command]
+async fn set_device_max_volume_percent(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String, percent: u8) -> Result<(), String> {
+  let volume = percent_to_volume(percent)?;
+  update_config(&app_handle, &audio, move |controller| controller.set_device_max_volume(&device_id, volume)).await
+}
+
+#[tauri::command]
+async fn set_global_max_volume_percent(
+  app_handle: tauri::AppHandle,
+  audio: State<'_, AudioThread>,
+  percent: u8,
+  data_flow: Option<DataFlow>
+) -> Result<Vec<DeviceApplyResult>, String> {
+  let volume = percent_to_volume(percent)?;
+  let data_flow = data_flow.unwrap_or_default();
+  update_config(&app_handle, &audio, move |controller| controller.set_global_max_volume(data_flow, volume)).await
+}
+
+#[tauri::command]
+async fn set_form_factor_max_volume(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, form_factor: FormFactor, volume: Option<f32>) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.set_form_factor_max_volume(form_factor, volume)).await
+}
+
+#[tauri::command]
+async fn set_new_headphone_default_max(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, volume: Option<f32>) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.set_new_headphone_default_max(volume)).await
+}
+
+#[tauri::command]
+async fn set_comms_max_volume(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, volume: Option<f32>) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.set_comms_max_volume(volume)).await
+}
+
+/// Sets the app whose audio ducks every other app, or `None` to stop ducking, and how much to duck by.
+#[tauri::command]
+async fn set_ducking(
+  app_handle: tauri::AppHandle,
+  audio: State<'_, AudioThread>,
+  priority_process: Option<String>,
+  duck_amount: f32
+) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.set_ducking(priority_process, duck_amount)).await
+}
+
+#[tauri::command]
+async fn set_device_alias(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String, alias: String) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_device_alias(&device_id, &alias);
+    Ok(())
+  }).await
+}
+
+#[tauri::command]
+async fn lock_device_volume(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String, volume: f32) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.lock_device_volume(&device_id, volume)).await
+}
+
+#[tauri::command]
+async fn unlock_device_volume(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.unlock_device_volume(&device_id);
+    Ok(())
+  }).await
+}
 
-  controller.set_device_max_volume(device_id, volume)?;
-  write_device_data(&app_handle, controller.into())?;
+/// Gives the device its own interval for enforcing limits, e.g. a shorter one for a device that other apps
+/// keep turning up. `None` goes back to the apply interval.
+#[tauri::command]
+async fn set_device_poll_interval(
+  app_handle: tauri::AppHandle,
+  audio: State<'_, AudioThread>,
+  device_id: String,
+  interval_ms: Option<u64>
+) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_device_poll_interval(&device_id, interval_ms);
+    Ok(())
+  }).await
+}
+
+/// Chooses whether the device has its volume lowered or gets muted when it goes over its cap.
+#[tauri::command]
+async fn set_device_exceed_policy(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String, policy: ExceedPolicy) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.set_device_exceed_policy(&device_id, policy)).await
+}
+
+#[tauri::command]
+async fn add_managed_device(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.add_managed_device(&device_id);
+    Ok(())
+  }).await
+}
+
+/// Leaves the device alone from now on. If every device was managed, every other known device stays managed.
+#[tauri::command]
+async fn remove_managed_device(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.remove_managed_device(&device_id);
+    Ok(())
+  }).await
+}
+
+/// Manages only the given devices, or every device if `device_ids` is `null`.
+#[tauri::command]
+async fn set_managed_devices(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_ids: Option<HashSet<String>>) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_managed_devices(device_ids);
+    Ok(())
+  }).await
+}
+
+/// Lets a device go above the global limit, e.g. an amplifier that's safe at any level, so only its own
+/// cap applies.
+#[tauri::command]
+async fn set_device_ignore_global(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String, ignore_global: bool) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_device_ignore_global(&device_id, ignore_global);
+    Ok(())
+  }).await
+}
+
+/// Turns enforcement for a single device on or off, keeping its configured limit.
+#[tauri::command]
+async fn set_device_limit_enabled(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String, enabled: bool) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_device_limit_enabled(&device_id, enabled);
+    Ok(())
+  }).await
+}
+
+#[tauri::command]
+async fn set_device_mute(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String, muted: bool) -> Result<(), String> {
+  let devices = audio.call(move |controller| {
+    controller.set_device_mute(&device_id, muted)?;
+    Ok::<_, String>(controller.get_devices())
+  }).await??;
+
+  app_handle.emit("devices-updated", DevicesUpdatedEvent { devices, changes: None }).map_err(|err| format!("{err}"))?;
   Ok(())
 }
 
+/// Lets the device go up to `volume` for `minutes`, whatever its saved cap and the global limit, after
+/// which `override-expired` is emitted and the saved cap applies again. Overrides aren't saved.
+#[tauri::command]
+async fn override_device_max_volume(
+  app_handle: tauri::AppHandle,
+  audio: State<'_, AudioThread>,
+  device_id: String,
+  volume: f32,
+  minutes: u32
+) -> Result<(), String> {
+  if minutes == 0 {
+    return Err("Override must last at least a minute".to_string());
+  }
+  let duration = Duration::from_secs(u64::from(minutes) * 60);
+  let devices = audio.call(move |controller| {
+    controller.override_device_max_volume(&device_id, volume, duration)?;
+    Ok::<_, String>(controller.get_devices())
+  }).await??;
+
+  app_handle.emit("devices-updated", DevicesUpdatedEvent { devices, changes: None }).map_err(|err| format!("{err}"))?;
+  Ok(())
+}
+
+/// Ends the device's override early. Returns whether it had one.
+#[tauri::command]
+async fn cancel_override(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_id: String) -> Result<bool, String> {
+  let (cancelled, devices) = audio.call(move |controller| {
+    let cancelled = controller.cancel_override(&device_id);
+    (cancelled, controller.get_devices())
+  }).await?;
+
+  if cancelled {
+    app_handle.emit("devices-updated", DevicesUpdatedEvent { devices, changes: None }).map_err(|err| format!("{err}"))?;
+  }
+  Ok(cancelled)
+}
+
+/// Updates the device list right away instead of waiting for the next periodic update.
+#[tauri::command]
+async fn refresh_devices(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>) -> Result<DevicesUpdatedEvent, String> {
+  let tick_handle = app_handle.clone();
+  let (outcome, result) = audio.call(move |controller| {
+    let mut outcome = TickOutcome::default();
+    let result = update_device_list(&tick_handle, controller, &mut outcome, false)
+      .map(|changes| DevicesUpdatedEvent { devices: controller.get_devices(), changes: Some(changes) });
+    (outcome, result)
+  }).await?;
+  handle_tick_outcome(&app_handle, outcome);
+
+  let event = result?;
+  update_tray_devices(&app_handle, &event.devices)?;
+  app_handle.emit("devices-updated", event.clone()).map_err(|err| format!("{err}"))?;
+  Ok(event)
+}
+
+#[tauri::command]
+async fn get_default_device(audio: State<'_, AudioThread>) -> Result<Option<AudioDeviceInfo>, String> {
+  audio.call(|controller| controller.get_default_device()).await?
+}
+
+/// Lists the apps playing through or recording from a device. Doesn't change any session.
+#[tauri::command]
+async fn get_device_sessions(audio: State<'_, AudioThread>, device_id: String) -> Result<Vec<SessionInfo>, String> {
+  audio.call(move |controller| controller.get_device_sessions(&device_id)).await?
+}
+
+/// Lists every endpoint Windows reports, including ones `get_devices` leaves out. For diagnostics only.
+#[tauri::command]
+async fn get_all_endpoints(audio: State<'_, AudioThread>, include_inactive: bool) -> Result<Vec<EndpointInfo>, String> {
+  audio.call(move |controller| controller.get_all_endpoints(include_inactive)).await?
+}
+
+#[tauri::command]
+async fn get_devices(audio: State<'_, AudioThread>) -> Result<Vec<AudioDeviceInfo>, String> {
+  audio.call(|controller| controller.get_devices()).await
+}
+
+#[derive(serde::Serialize)]
+struct ConfigSnapshot {
+  config: AudioDeviceConfig,
+  /// Increases with every config change. Pass it to `set_config` to make sure nothing changed in between.
+  revision: u64
+}
+
+/// Returns the whole config as one consistent snapshot, along with its revision.
+#[tauri::command]
+async fn get_config(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>) -> Result<ConfigSnapshot, String> {
+  audio.call(move |controller| {
+    // Revisions are only bumped on the audio thread, so this can't race with a config change.
+    let revision = app_handle.state::<Mutex<AppState>>().lock().unwrap().config_revision;
+    ConfigSnapshot { config: controller.get_config(), revision }
+  }).await
+}
+
+/// Replaces the whole config, unless it has changed since `expected_revision` was read from `get_config`.
+/// Rejecting the write keeps one part of the UI from clobbering a change made by another.
+#[tauri::command]
+async fn set_config(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, mut config: AudioDeviceConfig, expected_revision: u64) -> Result<(), String> {
+  config.validate()?;
+  let revision_handle = app_handle.clone();
+  update_config(&app_handle, &audio, move |controller| {
+    let revision = revision_handle.state::<Mutex<AppState>>().lock().unwrap().config_revision;
+    if revision != expected_revision {
+      return Err(format!("Conflict: the config changed since revision {expected_revision} and is now at revision {revision}"));
+    }
+    config.seen_device_ids = controller.get_config().seen_device_ids;
+    controller.set_config(config);
+    Ok(())
+  }).await
+}
+
+/// Merges the provided fields into the current config and returns the result. Only the given fields
+/// change, so concurrent updates to other fields aren't lost.
 #[tauri::command]
-fn set_global_max_volume(app_handle: tauri::AppHandle, volume: f32) -> Result<(), String> {
-  let state = app_handle.state::<Mutex<AppState>>();
-  let controller = &mut state.lock().unwrap().audio_controller;
-  controller.set_global_max_volume(volume)?;
-  write_device_data(&app_handle, controller.into())?;
+async fn patch_config(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, partial: PartialConfig) -> Result<AudioDeviceConfig, String> {
+  update_config(&app_handle, &audio, move |controller| {
+    let config = partial.merge_into(&controller.get_config())?;
+    config.validate()?;
+    controller.set_config(config.clone());
+    Ok(config)
+  }).await
+}
+
+/// Applies a profile file to the current config as `strategy` says. Returns the resulting config.
+#[tauri::command]
+async fn import_profile_file(
+  app_handle: tauri::AppHandle,
+  audio: State<'_, AudioThread>,
+  path: String,
+  strategy: MergeStrategy
+) -> Result<AudioDeviceConfig, String> {
+  let imported = read_profile_file(&path)?;
+  update_config(&app_handle, &audio, move |controller| {
+    let mut config = controller.get_config();
+    config.import(imported, strategy);
+    for fix in config.validate_and_clamp() {
+      log(LogLevel::Warn, format!("Fixed invalid imported config: {fix}"));
+    }
+    controller.set_config(config.clone());
+    Ok(config)
+  }).await
+}
+
+#[tauri::command]
+async fn export_profile_file(audio: State<'_, AudioThread>, path: String) -> Result<(), String> {
+  let config = audio.call(|controller| controller.get_config()).await?;
+  write_profile_file(&path, &config)
+}
+
+/// Restores the snapshot picked from the history by `pick`, which is given the current config. Returns
+/// whether there was anything to restore.
+async fn restore_config_snapshot(
+  app_handle: &tauri::AppHandle,
+  audio: &AudioThread,
+  pick: impl FnOnce(&mut ConfigHistory, AudioDeviceConfig) -> Option<AudioDeviceConfig>
+) -> Result<bool, String> {
+  let current = audio.call(|controller| controller.get_config()).await?;
+  let snapshot = pick(&mut app_handle.state::<Mutex<AppState>>().lock().unwrap().history, current);
+  let Some(mut snapshot) = snapshot else {
+    return Ok(false);
+  };
+
+  let revision_handle = app_handle.clone();
+  let (devices, config) = audio.call(move |controller| {
+    // Which devices have been seen is not a user setting, and forgetting them would re-trigger
+    // new device defaults.
+    snapshot.seen_device_ids = controller.get_config().seen_device_ids;
+    controller.set_config(snapshot);
+    bump_config_revision(&revision_handle);
+    (controller.get_devices(), controller.get_config())
+  }).await?;
+
+  update_tray(app_handle, &config, &devices)?;
+  sync_shortcuts(app_handle, &config.shortcuts);
+  schedule_device_data_write(app_handle, config.clone());
+  app_handle.emit("devices-updated", DevicesUpdatedEvent { devices, changes: None }).map_err(|err| format!("{err}"))?;
+  app_handle.emit("config-changed", config).map_err(|err| format!("{err}"))?;
+  Ok(true)
+}
+
+/// Loads device data that was edited outside the app, applies it like any other config change and
+/// emits `config-reloaded`.
+async fn reload_device_data(app_handle: tauri::AppHandle) -> Result<(), String> {
+  let Some(config) = read_changed_device_data(&app_handle)? else {
+    return Ok(());
+  };
+  // A write still waiting on its debounce would put the old config back over the file.
+  cancel_device_data_write(&app_handle);
+
+  let audio = audio_thread(&app_handle)?;
+  let revision_handle = app_handle.clone();
+  let (previous_config, devices, config) = audio.call(move |controller| {
+    let previous_config = controller.get_config();
+    controller.set_config(config);
+    bump_config_revision(&revision_handle);
+    (previous_config, controller.get_devices(), controller.get_config())
+  }).await?;
+
+  app_handle.state::<Mutex<AppState>>().lock().unwrap().history.push(previous_config);
+  update_tray(&app_handle, &config, &devices)?;
+  sync_shortcuts(&app_handle, &config.shortcuts);
+  app_handle.emit("devices-updated", DevicesUpdatedEvent { devices, changes: None }).map_err(|err| format!("{err}"))?;
+  app_handle.emit("config-changed", config.clone()).map_err(|err| format!("{err}"))?;
+  app_handle.emit("config-reloaded", config).map_err(|err| format!("{err}"))?;
   Ok(())
 }
 
+/// Replaces the config with the backup taken before the last save, e.g. to undo a bad edit to the file.
+/// The restore can itself be undone with `undo`.
 #[tauri::command]
-fn get_devices(state: State<'_, Mutex<AppState>>) -> Vec<AudioDeviceInfo> {
-  (&state).lock().unwrap().audio_controller.get_devices()
+async fn restore_backup(app_handle: tauri::AppHandle) -> Result<(), String> {
+  restore_device_data_backup(&app_handle)?;
+  reload_device_data(app_handle).await
 }
 
 #[tauri::command]
-fn get_global_max_volume(state: State<'_, Mutex<AppState>>) -> f32 {
-  (&state).lock().unwrap().audio_controller.get_global_max_volume()
+async fn undo(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>) -> Result<bool, String> {
+  restore_config_snapshot(&app_handle, &audio, |history, current| history.undo(current)).await
 }
 
+#[tauri::command]
+async fn redo(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>) -> Result<bool, String> {
+  restore_config_snapshot(&app_handle, &audio, |history, current| history.redo(current)).await
+}
+
+/// The app version, build profile and OS build, for the About dialog.
+#[tauri::command]
+fn app_info(app_handle: tauri::AppHandle) -> AppInfo {
+  read_app_info(&app_handle)
+}
+
+/// Collects device properties, the app and OS versions and the loaded config into a JSON blob for bug reports.
+#[tauri::command]
+async fn dump_diagnostics(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>) -> Result<String, String> {
+  let (devices, config) = audio.call(|controller| (controller.get_device_diagnostics(), controller.get_config())).await?;
+  let stats = app_handle.state::<Mutex<AppState>>().lock().unwrap().stats.get();
+  let diagnostics = serde_json::json!({
+    "app_version": app_handle.package_info().version.to_string(),
+    "app_info": read_app_info(&app_handle),
+    "config_app_version": config.app_version.clone(),
+    "config": config,
+    "devices": devices,
+    "stats": stats
+  });
+  serde_json::to_string_pretty(&diagnostics).map_err(|err| format!("{err}"))
+}
+
+#[tauri::command]
+async fn set_limiting_enabled(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, enabled: bool) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_limiting_enabled(enabled);
+    Ok(())
+  }).await
+}
+
+#[tauri::command]
+async fn get_limiting_enabled(audio: State<'_, AudioThread>) -> Result<bool, String> {
+  audio.call(|controller| controller.get_limiting_enabled()).await
+}
+
+/// Whether the welcome screen should be shown, which is only the case until it's acknowledged.
+#[tauri::command]
+async fn get_first_run(audio: State<'_, AudioThread>) -> Result<bool, String> {
+  audio.call(|controller| controller.get_first_run()).await
+}
+
+#[tauri::command]
+async fn acknowledge_first_run(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>) -> Result<(), String> {
+  update_config(&app_handle, &audio, |controller| {
+    controller.acknowledge_first_run();
+    Ok(())
+  }).await
+}
+
+#[tauri::command]
+async fn set_start_minimized(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, start_minimized: bool) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_start_minimized(start_minimized);
+    Ok(())
+  }).await
+}
+
+#[tauri::command]
+async fn set_close_behavior(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, close_behavior: CloseBehavior) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_close_behavior(close_behavior);
+    Ok(())
+  }).await
+}
+
+/// Changes which default device `is_default` and the default device commands refer to.
+#[tauri::command]
+async fn set_default_role(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, default_role: DeviceRole) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_default_role(default_role);
+    Ok(())
+  }).await
+}
+
+/// Takes effect the next time the app starts, as the audio thread can't change apartments while running.
+#[tauri::command]
+async fn set_com_apartment(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, com_apartment: ComApartment) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_com_apartment(com_apartment);
+    Ok(())
+  }).await
+}
+
+/// Reports what enforcement would do to each active device right now, without changing anything.
+#[tauri::command]
+async fn simulate_apply(audio: State<'_, AudioThread>) -> Result<Vec<SimResult>, String> {
+  audio.call(|controller| controller.simulate_apply()).await
+}
+
+/// Enforcement counters per device ID since the app started or the stats were last reset.
+#[tauri::command]
+fn get_stats(app_state: State<'_, Mutex<AppState>>) -> HashMap<String, DeviceStats> {
+  app_state.lock().unwrap().stats.get()
+}
+
+/// Volume corrections and mutes from the last `seconds`, oldest first. Warnings aren't included.
+#[tauri::command]
+fn recent_enforcements(app_state: State<'_, Mutex<AppState>>, seconds: u64) -> Vec<EnforcementRecord> {
+  app_state.lock().unwrap().stats.recent(seconds)
+}
+
+#[tauri::command]
+fn reset_stats(app_state: State<'_, Mutex<AppState>>) {
+  app_state.lock().unwrap().stats.reset();
+}
+
+/// Whether to show a toast when a limit is enforced. `notify_in_warn_only` only matters while
+/// `notify_on_enforce` is set.
+#[tauri::command]
+async fn set_notify_on_enforce(
+  app_handle: tauri::AppHandle,
+  audio: State<'_, AudioThread>,
+  notify_on_enforce: bool,
+  notify_in_warn_only: bool
+) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_notify_on_enforce(notify_on_enforce, notify_in_warn_only);
+    Ok(())
+  }).await
+}
+
+/// Switches between enforcing limits and only emitting `limit-exceeded` when a device goes over its limit.
+#[tauri::command]
+async fn set_limit_mode(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, mode: LimitMode) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_mode(mode);
+    Ok(())
+  }).await
+}
+
+/// Sets how often devices are re-enumerated and how often limits are enforced. Values below the minimum
+/// are raised to it.
+#[tauri::command]
+async fn set_intervals(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, update_interval_ms: u64, apply_interval_ms: u64) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_intervals(update_interval_ms, apply_interval_ms);
+    Ok(())
+  }).await
+}
+
+/// Limits how far enforcement moves a volume per tick, e.g. 0.05 to ramp down in 5% steps. 0 jumps
+/// straight to the cap.
+#[tauri::command]
+async fn set_max_step_per_tick(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, step: f32) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.set_max_step_per_tick(step)).await
+}
+
+/// Takes effect on the next device update.
+#[tauri::command]
+async fn set_include_disabled_devices(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, include_disabled_devices: bool) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_include_disabled_devices(include_disabled_devices);
+    Ok(())
+  }).await
+}
+
+/// Changes how much is logged to the console, e.g. `debug` to see every enforcement decision.
+#[tauri::command]
+async fn set_log_level(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, level: LogLevel) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_log_level(level);
+    Ok(())
+  }).await
+}
+
+#[tauri::command]
+async fn set_sort_order(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, sort_order: SortOrder) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_sort_order(sort_order);
+    Ok(())
+  }).await
+}
+
+/// Saves the order devices were arranged in, e.g. by dragging them in the UI, and lists them in that order.
+/// Saves the current limits as a profile, which is activated automatically once every device in
+/// `auto_activate_when_present` is present.
+#[tauri::command]
+async fn save_profile(
+  app_handle: tauri::AppHandle,
+  audio: State<'_, AudioThread>,
+  name: String,
+  auto_activate_when_present: Vec<String>
+) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| controller.save_profile(&name, auto_activate_when_present)).await
+}
+
+#[tauri::command]
+async fn delete_profile(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, name: String) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.delete_profile(&name);
+    Ok(())
+  }).await
+}
+
+/// Sets which profile wins when the devices of several are present. Earlier profiles go first.
+#[tauri::command]
+async fn set_profile_order(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, names: Vec<String>) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_profile_order(names);
+    Ok(())
+  }).await
+}
+
+/// Switches to a profile by hand. It stays active until a device change activates another profile, and is
+/// switched back to once no profile's devices are present.
+#[tauri::command]
+async fn activate_profile(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, name: String) -> Result<(), String> {
+  let activated_name = name.clone();
+  update_config(&app_handle, &audio, move |controller| controller.activate_profile(&activated_name)).await?;
+  app_handle.emit("profile-activated", ProfileActivatedEvent { name, automatic: false }).map_err(|err| format!("{err}"))
+}
+
+#[tauri::command]
+async fn set_device_order(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, device_ids: Vec<String>) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_device_order(device_ids);
+    Ok(())
+  }).await
+}
+
+/// Tries to start the audio thread again after it failed at startup, enforcing limits right away like a
+/// normal start does. Devices follow in `startup-ready` once it's running.
+#[tauri::command]
+async fn retry_init(app_handle: tauri::AppHandle) -> Result<(), String> {
+  // Keeps concurrent retries from starting two audio threads. `AppState` isn't held for this, as COM
+  // initialization can take a while and every other command needs it.
+  static RETRYING: Mutex<()> = Mutex::new(());
+
+  let apply_all_on_startup = {
+    let _retrying = RETRYING.lock().unwrap();
+    if app_handle.try_state::<AudioThread>().is_some() {
+      return Ok(());
+    }
+
+    let event_context = app_handle.state::<Mutex<AppState>>().lock().unwrap().event_context;
+    let result = read_device_data(&app_handle)
+      .and_then(|config| {
+        let apply_all_on_startup = config.apply_all_on_startup;
+        start_audio(&app_handle, config, event_context).map(|_| apply_all_on_startup)
+      })
+      .map_err(|err| format!("Couldn't initialize audio: {err}"));
+    app_handle.state::<Mutex<AppState>>().lock().unwrap().startup_error = result.clone().err();
+    result?
+  };
+
+  if apply_all_on_startup {
+    enforce_on_startup(app_handle).await;
+  }
+  Ok(())
+}
+
+/// Payload of `health_check`.
+#[derive(serde::Serialize)]
+struct HealthStatus {
+  /// Whether the audio thread answered a ping within `HEALTH_PING_TIMEOUT`. See `get_startup_error` for why
+  /// it didn't start.
+  audio_running: bool,
+  /// Whether the last device update succeeded.
+  enumerator_alive: bool,
+  /// How many devices the last successful update found.
+  device_count: usize,
+  /// Milliseconds since the Unix epoch of the last successful device update.
+  last_poll_at: Option<u64>,
+  /// The most recent error from the periodic tasks.
+  last_error: Option<String>
+}
+
+/// How long `health_check` waits for the audio thread to answer. A thread stuck in a job counts as not
+/// running.
+const HEALTH_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Reports whether the audio thread answers, and the state of the audio subsystem as last seen by the
+/// periodic tasks. Doesn't touch any device.
+#[tauri::command]
+async fn health_check(app_handle: tauri::AppHandle) -> HealthStatus {
+  let audio_running = match audio_thread(&app_handle) {
+    Ok(audio) => matches!(tokio::time::timeout(HEALTH_PING_TIMEOUT, audio.call(|_| ())).await, Ok(Ok(()))),
+    Err(_) => false
+  };
+  let app_state = app_handle.state::<Mutex<AppState>>();
+  let app_state = app_state.lock().unwrap();
+  HealthStatus {
+    audio_running,
+    enumerator_alive: audio_running && app_state.health.enumerator_alive,
+    device_count: app_state.health.device_count,
+    last_poll_at: app_state.health.last_poll_at,
+    last_error: app_state.health.last_error.clone().or_else(|| app_state.startup_error.clone())
+  }
+}
+
+/// The error that kept the audio thread from starting, if it isn't running.
+#[tauri::command]
+fn get_startup_error(app_state: State<'_, Mutex<AppState>>) -> Option<String> {
+  app_state.lock().unwrap().startup_error.clone()
+}
+
+/// What was done to bring devices within their limits when audio started.
+#[tauri::command]
+fn get_startup_enforcement(app_state: State<'_, Mutex<AppState>>) -> Option<StartupEnforcement> {
+  app_state.lock().unwrap().startup_enforcement.clone()
+}
+
+#[tauri::command]
+async fn set_volume_curve(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, volume_curve: VolumeCurve) -> Result<(), String> {
+  update_config(&app_handle, &audio, move |controller| {
+    controller.set_volume_curve(volume_curve);
+    Ok(())
+  }).await
+}
+
+/// Replaces the global limit shortcuts. Both accelerators are checked up front, so a typo is rejected
+/// instead of being saved.
+#[tauri::command]
+async fn set_shortcuts(app_handle: tauri::AppHandle, audio: State<'_, AudioThread>, shortcuts: ShortcutConfig) -> Result<(), String> {
+  parse_shortcut(&shortcuts.raise)?;
+  parse_shortcut(&shortcuts.lower)?;
+  update_config(&app_handle, &audio, move |controller| controller.set_shortcuts(shortcuts)).await
+}
+
+/// Slider position for a scalar volume under the configured volume curve.
+#[tauri::command]
+async fn volume_to_display(audio: State<'_, AudioThread>, volume: f32) -> Result<f32, String> {
+  validate_volume(volume)?;
+  let volume_curve = audio.call(|controller| controller.get_config().volume_curve).await?;
+  Ok(scalar_to_display(volume, volume_curve))
+}
+
+/// Scalar volume for a slider position under the configured volume curve.
+#[tauri::command]
+async fn display_to_volume(audio: State<'_, AudioThread>, display: f32) -> Result<f32, String> {
+  validate_volume(display)?;
+  let volume_curve = audio.call(|controller| controller.get_config().volume_curve).await?;
+  Ok(display_to_scalar(display, volume_curve))
+}
+
+#[tauri::command]
+async fn get_global_max_volume(audio: State<'_, AudioThread>, data_flow: Option<DataFlow>) -> Result<f32, String> {
+  let data_flow = data_flow.unwrap_or_default();
+  audio.call(move |controller| controller.get_global_max_volume(data_flow)).await
+}
+
+/// Payload of the `profile-activated` event.
+#[derive(Clone, serde::Serialize)]
+struct ProfileActivatedEvent {
+  name: String,
+  /// Whether the profile was activated because its devices became present, rather than by hand.
+  automatic: bool
+}
+
+/// Payload of the `devices-updated` event. `changes` is set when the update came from device
+/// enumeration, so the frontend can update only the affected devices.
+#[derive(Clone, serde::Serialize)]
+struct DevicesUpdatedEvent {
+  devices: Vec<AudioDeviceInfo>,
+  changes: Option<DeviceChanges>
+}
+
+/// Saves pending config changes and exits the app.
+fn exit_app(app_handle: &tauri::AppHandle) {
+  if let Err(err) = flush_device_data(app_handle) {
+    log(LogLevel::Error, format!("Couldn't save device data: {err}"));
+  }
+  if let Err(err) = save_window_geometry(app_handle) {
+    log(LogLevel::Error, format!("Couldn't save window geometry: {err}"));
+  }
+  if let Ok(audio) = audio_thread(app_handle) {
+    audio.shutdown();
+  }
+  app_handle.exit(0);
+}
+
+/// Hides the window to the tray or exits, depending on the configured `close_behavior`. Reading the
+/// config needs a trip to the audio thread, so the close is always prevented and then carried out here.
+async fn close_main_window(window: tauri::Window) {
+  let app_handle = window.app_handle().clone();
+  let close_behavior = match audio_thread(&app_handle) {
+    Ok(audio) => audio.call(|controller| controller.get_config().close_behavior).await.unwrap_or(CloseBehavior::Tray),
+    Err(_) => CloseBehavior::Tray
+  };
+
+  match close_behavior {
+    CloseBehavior::Tray => {
+      if let Err(err) = save_window_geometry(&app_handle) {
+        emit_error(&app_handle, AppError::new(ErrorCode::Window, format!("Couldn't save window geometry: {err}")));
+      }
+      window.hide().unwrap()
+    },
+    CloseBehavior::Exit => exit_app(&app_handle)
+  }
+}
+
+/// Starts the app in the tray regardless of `start_minimized`. The app doesn't register itself to run at
+/// login, so this is for whatever launches it then, e.g. a Task Scheduler task or a shortcut in the Startup folder.
+const AUTOSTART_ARG: &str = "--autostart";
+/// Skips the single-instance check, so another instance can run alongside. Only honored together with a
+/// data directory override, as two instances sharing one config would overwrite each other's changes.
+const ALLOW_MULTIPLE_ARG: &str = "--allow-multiple";
+
 struct AppState {
-  audio_controller: audio::AudioController
+  /// Event context attached to every volume and mute change we make. Endpoint notification callbacks
+  /// must ignore notifications carrying this context, or the app would react to its own corrections.
+  event_context: GUID,
+  history: ConfigHistory,
+  stats: EnforcementStats,
+  /// Why the audio thread couldn't be started, until a `retry_init` succeeds.
+  startup_error: Option<String>,
+  /// What enforcing limits at startup did, once it's done. `None` if `apply_all_on_startup` is off.
+  startup_enforcement: Option<StartupEnforcement>,
+  /// Bumped with every config change. Kept in memory only; it just has to tell reads apart within a run.
+  config_revision: u64,
+  /// What the periodic tasks last saw, for `health_check`.
+  health: PollHealth
 }
-unsafe impl Send for AppState {}
 
-async fn run_periodic(interval_ms: u64, cb: impl Fn() + Send + 'static) {
-  loop {
-    cb();
-    tokio::time::sleep(tokio::time::Duration::from_millis(interval_ms)).await;
+#[derive(Default)]
+struct PollHealth {
+  /// Whether the last device update succeeded.
+  enumerator_alive: bool,
+  device_count: usize,
+  /// Milliseconds since the Unix epoch of the last successful device update.
+  last_poll_at: Option<u64>,
+  last_error: Option<String>
+}
+
+/// Marks the config as changed. Must be called on the audio thread, in the same job as the change, so
+/// that revision checks made in other jobs see the change and its revision together.
+fn bump_config_revision(app_handle: &tauri::AppHandle) {
+  app_handle.state::<Mutex<AppState>>().lock().unwrap().config_revision += 1;
+}
+
+/// The running audio thread. It's missing if it failed to start and hasn't been retried successfully.
+fn audio_thread(app_handle: &tauri::AppHandle) -> Result<AudioThread, String> {
+  app_handle.try_state::<AudioThread>()
+    .map(|audio| audio.inner().clone())
+    .ok_or_else(|| "Audio isn't initialized".to_string())
+}
+
+/// Starts the audio thread with `config` and the periodic tasks that run on it.
+fn start_audio(app_handle: &tauri::AppHandle, config: AudioDeviceConfig, event_context: GUID) -> Result<(), String> {
+  let (update_interval_ms, apply_interval_ms) = (config.update_interval_ms, config.apply_interval_ms);
+  let com_apartment = config.com_apartment;
+  app_handle.manage(AudioThread::spawn(com_apartment, move || AudioController::init(config, event_context))?);
+  tauri::async_runtime::spawn(run_periodic_tasks(update_interval_ms, apply_interval_ms, app_handle.clone()));
+  Ok(())
+}
+
+/// What a periodic tick did, to be persisted and emitted once the tick is back off the audio thread.
+#[derive(Default)]
+struct TickOutcome {
+  devices_updated: Option<DevicesUpdatedEvent>,
+  auto_limited: Vec<AudioDeviceInfo>,
+  /// The profile switched to because its devices became present, or away from because they're gone.
+  activated_profile: Option<String>,
+  config: Option<AudioDeviceConfig>,
+  enumerator_recovered: bool,
+  /// Whether the devices were rebuilt after a resume from sleep.
+  reinitialized: bool,
+  /// Whether the devices were rebuilt because they had all gone invalid, e.g. after the audio service restarted.
+  subsystem_reinitialized: bool,
+  update_interval_ms: u64,
+  apply_interval_ms: u64,
+  startup_ready: Option<StartupReadyEvent>,
+  enforcements: Vec<Enforcement>,
+  /// The number of devices after a successful device update, or `None` if there was no update or it failed.
+  polled_device_count: Option<usize>,
+  /// Whether a device update was attempted and failed.
+  poll_failed: bool,
+  /// Whether there were no limits to enforce, so ticks can slow down until there are.
+  idle: bool,
+  volume_changes: Vec<VolumeChange>,
+  /// Devices whose override ran out.
+  expired_overrides: Vec<String>,
+  /// The config enforcements were made with, set when there were any.
+  enforcement_config: Option<AudioDeviceConfig>,
+  errors: Vec<AppError>
+}
+
+fn apply_volume_limits(controller: &mut AudioController, outcome: &mut TickOutcome) {
+  if !controller.has_limits() {
+    outcome.idle = true;
+    return;
+  }
+
+  for device_id in controller.limited_device_ids() {
+    if !controller.take_poll_due(&device_id) {
+      continue;
+    }
+    let result = match controller.should_enforce(&device_id) {
+      Ok(true) => controller.apply_max_volume(&device_id),
+      Ok(false) => Ok(None),
+      Err(err) => Err(err)
+    };
+    match result {
+      Ok(enforcement) => outcome.enforcements.extend(enforcement),
+      Err(err) => outcome.errors.push(enforcement_error(controller, &device_id, err))
+    }
   }
 }
 
-async fn periodic_update_devices(interval_ms: u64, app_handle: tauri::AppHandle) {
-  run_periodic(interval_ms, move || {
-    let state = app_handle.state::<Mutex<AppState>>();
-    let controller = &mut state.lock().unwrap().audio_controller;
+fn enforcement_error(controller: &AudioController, device_id: &str, err: String) -> AppError {
+  AppError::for_device(
+    ErrorCode::Enforcement,
+    device_id,
+    format!("Couldn't apply volume limit to device '{}': {err}", controller.get_device_name(device_id))
+  )
+}
+
+/// Updates the device list, or rebuilds it from scratch if `reinitialize` is set, applies new device
+/// defaults to devices that appeared, and enforces limits on devices that became active right away.
+fn update_device_list(
+  app_handle: &tauri::AppHandle,
+  controller: &mut AudioController,
+  outcome: &mut TickOutcome,
+  reinitialize: bool
+) -> Result<DeviceChanges, String> {
+  let changes = match reinitialize {
+    true => controller.reinitialize(),
+    false => controller.update_devices()
+  };
+  outcome.enumerator_recovered = controller.take_enumerator_recovered();
+  let changes = changes.map_err(|err| format!("Couldn't update audio devices: {err}"))?;
 
-    match controller.update_devices() {
-      Err(err) => app_handle.emit("error", format!("Couldn't update audio devices: {err}")).unwrap(),
-      Ok(true) => app_handle.emit("devices-updated", &controller.get_devices()).unwrap(),
-      Ok(false) => {}
+  let (defaults_changed, auto_limited) = controller.apply_new_device_defaults(&changes.added);
+  if defaults_changed {
+    outcome.auto_limited = controller.get_devices().into_iter()
+      .filter(|device| auto_limited.contains(&device.id))
+      .collect();
+  }
+  outcome.activated_profile = controller.auto_activate_profile();
+  if defaults_changed || outcome.activated_profile.is_some() {
+    outcome.config = Some(controller.get_config());
+    bump_config_revision(app_handle);
+  }
+  // A re-enabled device can come back at full volume, so its cap is applied without waiting for its poll
+  // interval or for it to start playing.
+  for device_id in &changes.activated {
+    match controller.apply_max_volume(device_id) {
+      Ok(enforcement) => outcome.enforcements.extend(enforcement),
+      Err(err) => outcome.errors.push(enforcement_error(controller, device_id, err))
     }
-  }).await;
+  }
+  Ok(changes)
+}
+
+/// What `enforce_on_startup` did, from `get_startup_enforcement` and in `startup-enforced`.
+#[derive(Clone, serde::Serialize)]
+struct StartupEnforcement {
+  /// How many devices have a limit to enforce.
+  limited_device_count: usize,
+  /// The devices that were off their limit, and what was done about them.
+  enforcements: Vec<Enforcement>
 }
 
-async fn periodic_apply_volume_limits(interval_ms: u64, app_handle: tauri::AppHandle) {
-  run_periodic(interval_ms, move || {
-    let state = app_handle.state::<Mutex<AppState>>();
-    let controller = &mut state.lock().unwrap().audio_controller;
-    for device in controller.get_devices() {
-      match controller.apply_max_volume(&device.id) {
-        Err(err) => app_handle.emit("error", format!("Couldn't apply volume limit to device '{}': {err}", device.name)).unwrap(),
-        Ok(()) => {}
+/// Enumerates the devices and enforces every limit as soon as audio has started, rather than leaving
+/// devices that were loud before the app started to the first tick.
+async fn enforce_on_startup(app_handle: tauri::AppHandle) {
+  let audio = match audio_thread(&app_handle) {
+    Ok(audio) => audio,
+    Err(err) => {
+      log(LogLevel::Error, format!("Couldn't enforce limits on startup: {err}"));
+      return;
+    }
+  };
+  let startup_handle = app_handle.clone();
+  let result = audio.call(move |controller| {
+    let mut outcome = TickOutcome::default();
+    match update_device_list(&startup_handle, controller, &mut outcome, false) {
+      Ok(_) => outcome.polled_device_count = Some(controller.device_count()),
+      Err(err) => {
+        outcome.poll_failed = true;
+        outcome.errors.push(AppError::new(ErrorCode::DeviceUpdate, err));
+      }
+    }
+    let device_ids = controller.limited_device_ids();
+    for device_id in &device_ids {
+      match controller.apply_max_volume(device_id) {
+        Ok(enforcement) => outcome.enforcements.extend(enforcement),
+        Err(err) => outcome.errors.push(enforcement_error(controller, device_id, err))
       }
     }
+    if !outcome.enforcements.is_empty() {
+      outcome.enforcement_config = Some(controller.get_config());
+    }
+    (device_ids.len(), outcome)
   }).await;
+
+  match result {
+    Ok((limited_device_count, outcome)) => {
+      let enforcement = StartupEnforcement { limited_device_count, enforcements: outcome.enforcements.clone() };
+      handle_tick_outcome(&app_handle, outcome);
+      app_handle.state::<Mutex<AppState>>().lock().unwrap().startup_enforcement = Some(enforcement.clone());
+      // Only reaches a window that's already listening, i.e. after `retry_init`. At startup the window
+      // isn't loaded yet and reads it with `get_startup_enforcement` instead.
+      if let Err(err) = app_handle.emit("startup-enforced", enforcement) {
+        log(LogLevel::Error, format!("Couldn't emit startup enforcement: {err}"));
+      }
+    },
+    Err(err) => log(LogLevel::Error, format!("Couldn't enforce limits on startup: {err}"))
+  }
+}
+
+/// Persists, records and emits what a tick did.
+fn handle_tick_outcome(app_handle: &tauri::AppHandle, outcome: TickOutcome) {
+  {
+    let app_state = app_handle.state::<Mutex<AppState>>();
+    let health = &mut app_state.lock().unwrap().health;
+    if let Some(device_count) = outcome.polled_device_count {
+      health.enumerator_alive = true;
+      health.device_count = device_count;
+      health.last_poll_at = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|elapsed| elapsed.as_millis() as u64);
+    }
+    if outcome.poll_failed {
+      health.enumerator_alive = false;
+    }
+    if let Some(err) = outcome.errors.last() {
+      health.last_error = Some(err.message.clone());
+    }
+  }
+  if let Some(config) = outcome.config {
+    if outcome.activated_profile.is_some() {
+      app_handle.emit("config-changed", &config).unwrap();
+    }
+    schedule_device_data_write(app_handle, config);
+  }
+  if !outcome.enforcements.is_empty() {
+    let app_state = app_handle.state::<Mutex<AppState>>();
+    let mut app_state = app_state.lock().unwrap();
+    outcome.enforcements.iter()
+      .filter(|enforcement| enforcement.action != EnforcementAction::Warned)
+      .for_each(|enforcement| app_state.stats.record(enforcement));
+  }
+  for enforcement in outcome.enforcements.iter().filter(|enforcement| enforcement.action == EnforcementAction::Warned) {
+    app_handle.emit("limit-exceeded", enforcement).unwrap();
+  }
+  if let Some(config) = outcome.enforcement_config {
+    notify_enforcements(app_handle, &config, &outcome.enforcements);
+  }
+  if outcome.enumerator_recovered {
+    app_handle.emit("enumerator-recovered", ()).unwrap();
+  }
+  if outcome.reinitialized {
+    app_handle.emit("resumed-reinitialized", ()).unwrap();
+  }
+  if outcome.subsystem_reinitialized {
+    app_handle.emit("audio-subsystem-reinitialized", ()).unwrap();
+  }
+  if let Some(event) = outcome.devices_updated {
+    if let Err(err) = update_tray_devices(app_handle, &event.devices) {
+      emit_error(app_handle, AppError::new(ErrorCode::Tray, err));
+    }
+    // Sent alongside the full list, so the frontend can react to a single device coming or going.
+    if let Some(changes) = &event.changes {
+      for device in event.devices.iter().filter(|device| changes.added.contains(&device.id)) {
+        app_handle.emit("device-added", device).unwrap();
+      }
+      for device_id in &changes.removed {
+        app_handle.emit("device-removed", device_id).unwrap();
+      }
+      for device in event.devices.iter().filter(|device| changes.activated.contains(&device.id)) {
+        app_handle.emit("device-activated", device).unwrap();
+      }
+    }
+    app_handle.emit("devices-updated", event).unwrap();
+  }
+  if !outcome.auto_limited.is_empty() {
+    app_handle.emit("headphones-auto-limited", outcome.auto_limited).unwrap();
+  }
+  if let Some(name) = outcome.activated_profile {
+    app_handle.emit("profile-activated", ProfileActivatedEvent { name, automatic: true }).unwrap();
+  }
+  for volume_change in outcome.volume_changes {
+    app_handle.emit("volume-changed", volume_change).unwrap();
+  }
+  for device_id in outcome.expired_overrides {
+    app_handle.emit("override-expired", device_id).unwrap();
+  }
+  outcome.errors.into_iter().for_each(|err| emit_error(app_handle, err));
+}
+
+/// How much longer than the tick interval the wall clock has to advance between ticks to count as a
+/// resume from sleep.
+///
+/// This is a heuristic rather than a power notification: anything else that stalls the loop or moves the
+/// wall clock forward by this much, such as a slow audio job or an NTP correction, counts as a resume too.
+/// That's accepted, as a false positive only costs one device rebuild, and it saves a hidden window just
+/// to receive `WM_POWERBROADCAST`.
+const RESUME_DETECTION_GAP: Duration = Duration::from_secs(5);
+
+/// Payload of the `startup-ready` event, emitted once the first devices have been enumerated.
+#[derive(serde::Serialize)]
+struct StartupReadyEvent {
+  config: AudioDeviceConfig,
+  devices: Vec<AudioDeviceInfo>
+}
+
+/// Runs device updates and limit enforcement from a single loop, so each tick makes one trip to the
+/// audio thread and devices are always updated before limits are applied to them. The loop ticks every
+/// `apply_interval_ms`, and devices are updated on the first tick after `update_interval_ms` has elapsed.
+/// Both intervals are re-read from the config every tick, so changing them takes effect right away.
+///
+/// Ticks never overlap, since each one is awaited before the next is started. A tick that takes longer
+/// than the interval, e.g. while COM is slow during a device switch, makes the ticks it ran over get
+/// skipped rather than run back to back to catch up, as enforcing the same limits several times in a row
+/// gains nothing.
+async fn run_periodic_tasks(update_interval_ms: u64, apply_interval_ms: u64, app_handle: tauri::AppHandle) {
+  let audio = app_handle.state::<AudioThread>().inner().clone();
+  let mut update_interval = Duration::from_millis(update_interval_ms);
+  // While there are no limits to enforce, ticks only need to keep the device list current. Setting a limit
+  // applies it right away, and the next tick goes back to the apply interval.
+  let mut tick_interval = Duration::from_millis(apply_interval_ms);
+  let mut last_update: Option<Instant> = None;
+  let mut last_tick = SystemTime::now();
+  let mut first_tick = true;
+  // Set after a tick with errors, which is how devices going invalid shows up.
+  let mut check_invalidated = false;
+  let mut ticker = tokio::time::interval(tick_interval);
+  ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+  loop {
+    ticker.tick().await;
+    // The loop doesn't run while the system is asleep, so a wall clock jump much larger than the tick
+    // interval means we just resumed.
+    let resumed = SystemTime::now()
+      .duration_since(last_tick)
+      .is_ok_and(|elapsed| elapsed > tick_interval + RESUME_DETECTION_GAP);
+    last_tick = SystemTime::now();
+
+    let update = resumed || last_update.is_none_or(|last_update| last_update.elapsed() >= update_interval);
+    if update {
+      last_update = Some(Instant::now());
+    }
+
+    let tick_handle = app_handle.clone();
+    let result = audio.call(move |controller: &mut AudioController| {
+      let config = controller.get_config();
+      let mut outcome = TickOutcome {
+        update_interval_ms: config.update_interval_ms,
+        apply_interval_ms: config.apply_interval_ms,
+        ..TickOutcome::default()
+      };
+
+      let rebuild = check_invalidated && controller.take_rebuild_due();
+      if update || rebuild {
+        match update_device_list(&tick_handle, controller, &mut outcome, resumed || rebuild) {
+          Err(err) => {
+            outcome.poll_failed = true;
+            outcome.errors.push(AppError::new(ErrorCode::DeviceUpdate, err));
+          },
+          Ok(changes) => {
+            outcome.reinitialized = resumed;
+            outcome.subsystem_reinitialized = rebuild && !resumed;
+            outcome.polled_device_count = Some(controller.device_count());
+            if changes.is_changed() {
+              outcome.devices_updated = Some(DevicesUpdatedEvent { devices: controller.get_devices(), changes: Some(changes) });
+            }
+          }
+        }
+        // Session volumes are only polled with device updates; enumerating sessions every tick is too costly.
+        outcome.errors.extend(controller.apply_ducking().into_iter().map(|err| AppError::new(ErrorCode::Ducking, err)));
+      }
+
+      // Before enforcing, so a device goes back to its saved cap on the tick its override runs out.
+      outcome.expired_overrides = controller.take_expired_overrides();
+      if !outcome.expired_overrides.is_empty() && outcome.devices_updated.is_none() {
+        outcome.devices_updated = Some(DevicesUpdatedEvent { devices: controller.get_devices(), changes: None });
+      }
+      apply_volume_limits(controller, &mut outcome);
+      // Only checked along with device updates, so a slider being dragged in Windows produces a change
+      // per update interval rather than one per tick.
+      if update {
+        outcome.volume_changes = controller.take_volume_changes();
+      }
+      if first_tick {
+        outcome.startup_ready = Some(StartupReadyEvent {
+          config: controller.get_config(),
+          devices: controller.get_devices()
+        });
+      }
+      if !outcome.enforcements.is_empty() {
+        outcome.enforcement_config = Some(controller.get_config());
+      }
+      outcome
+    }).await;
+
+    match result {
+      Err(err) => {
+        let message = format!("Couldn't run periodic tasks: {err}");
+        {
+          let app_state = app_handle.state::<Mutex<AppState>>();
+          let health = &mut app_state.lock().unwrap().health;
+          health.enumerator_alive = false;
+          health.last_error = Some(message.clone());
+        }
+        emit_error(&app_handle, AppError::new(ErrorCode::AudioThread, message));
+      },
+      Ok(mut outcome) => {
+        update_interval = Duration::from_millis(outcome.update_interval_ms);
+        let apply_interval = Duration::from_millis(outcome.apply_interval_ms);
+        let next_tick_interval = match outcome.idle {
+          true => apply_interval.max(update_interval),
+          false => apply_interval
+        };
+        if tick_interval != next_tick_interval {
+          tick_interval = next_tick_interval;
+          ticker = tokio::time::interval_at(tokio::time::Instant::now() + tick_interval, tick_interval);
+          ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        }
+        if let Some(event) = outcome.startup_ready.take() {
+          first_tick = false;
+          app_handle.emit("startup-ready", event).unwrap();
+        }
+        check_invalidated = !outcome.errors.is_empty();
+        handle_tick_outcome(&app_handle, outcome);
+      }
+    }
+    flush_errors(&app_handle);
+  }
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-  Builder::default()
-    .setup(|app| {
-      let show_item = MenuItem::with_id(app, "show", "Show", true, None::<&str>)?;
-      let quit_item = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
-      let menu = Menu::with_items(app, &[&show_item, &quit_item])?;
-
-      TrayIconBuilder::new()
-        .menu(&menu)
-        .show_menu_on_left_click(false)
-        .icon(app.default_window_icon().unwrap().clone())
-        .tooltip("Volume Limiter")
-        .on_tray_icon_event(|tray, event| match event {
-          TrayIconEvent::DoubleClick { button: MouseButton::Left, .. } => {
-            let window = tray.app_handle().get_webview_window("main").unwrap();
-            window.show().unwrap();
-            window.set_focus().unwrap();
-          },
-          _ => {}
-        })
-        .on_menu_event(|app, event| match event.id.as_ref() {
-          "show" => {
-            let window = app.get_webview_window("main").unwrap();
-            window.show().unwrap();
-            window.set_focus().unwrap();
-          },
-          "quit" => app.exit(0),
-          _ => {}
-        })
-        .build(app)?;
+  let mut builder = Builder::default();
+  let allow_multiple = std::env::args().any(|arg| arg == ALLOW_MULTIPLE_ARG);
+  if allow_multiple && data_dir_override().is_none() {
+    log(LogLevel::Warn, format!("Ignoring {ALLOW_MULTIPLE_ARG}, which needs a separate data directory"));
+  }
+  // Registered first, so a second instance exits before any other plugin sets itself up.
+  if !allow_multiple || data_dir_override().is_none() {
+    builder = builder.plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| show_main_window(app)));
+  }
 
+  builder
+    .setup(|app| {
+      app.manage(Mutex::new(ErrorThrottle::default()));
+      app.manage(Mutex::new(NotificationThrottle::default()));
+      app.manage(DeviceDataWriter::default());
+      app.manage(Mutex::new(ShortcutBindings::default()));
       init_device_data(app.handle())?;
       let device_data = read_device_data(app.handle())?;
+      create_tray(app.handle(), &device_data)?;
+      if let Err(err) = restore_window_geometry(app.handle()) {
+        log(LogLevel::Warn, format!("Couldn't restore window geometry: {err}"));
+      }
+      sync_shortcuts(app.handle(), &device_data.shortcuts);
+
+      // The window is created hidden so it doesn't flash on screen before we know whether to show it.
+      let launched_by_autostart = std::env::args().any(|arg| arg == AUTOSTART_ARG);
+      let show_window = !device_data.start_minimized && !launched_by_autostart;
+      let apply_all_on_startup = device_data.apply_all_on_startup;
 
+      let event_context = GUID::new()?;
       app.manage(Mutex::new(AppState {
-        audio_controller: audio::AudioController::init(device_data)?
+        event_context,
+        history: ConfigHistory::default(),
+        stats: EnforcementStats::default(),
+        startup_error: None,
+        startup_enforcement: None,
+        config_revision: 0,
+        health: PollHealth::default()
       }));
 
-      tauri::async_runtime::spawn(periodic_update_devices(500, app.handle().clone()));
-      tauri::async_runtime::spawn(periodic_apply_volume_limits(50, app.handle().clone()));
+      let app_handle = app.handle().clone();
+      watch_device_data(app.handle(), move || {
+        let app_handle = app_handle.clone();
+        tauri::async_runtime::spawn(async move {
+          if let Err(err) = reload_device_data(app_handle.clone()).await {
+            emit_error(&app_handle, AppError::new(ErrorCode::DataReload, format!("Couldn't reload device data: {err}")));
+          }
+        });
+      })?;
+
+      // A broken audio stack shouldn't take the window and tray down with it. The error is shown in the
+      // window, which can call `retry_init`.
+      match start_audio(app.handle(), device_data, event_context) {
+        Ok(()) if apply_all_on_startup => {
+          tauri::async_runtime::block_on(enforce_on_startup(app.handle().clone()));
+        },
+        Ok(()) => {},
+        Err(err) => {
+          let message = format!("Couldn't initialize audio: {err}");
+          log(LogLevel::Error, &message);
+          app.state::<Mutex<AppState>>().lock().unwrap().startup_error = Some(message.clone());
+          app.emit("startup-error", message)?;
+        }
+      }
+      // Shown only once audio has started, so that with `apply_all_on_startup` limits are already in force.
+      if show_window {
+        show_main_window(app.handle());
+      }
 
       Ok(())
     })
     .on_window_event(|window, event| match event {
       WindowEvent::CloseRequested { api, .. } => {
-        window.hide().unwrap();
         api.prevent_close();
+        tauri::async_runtime::spawn(close_main_window(window.clone()));
       },
       _ => {}
     })
-    .plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| {
-      let _ = app.get_webview_window("main")
-        .expect("no main window")
-        .set_focus();
-    }))
     .plugin(tauri_plugin_opener::init())
-    .invoke_handler(tauri::generate_handler![set_device_max_volume, set_global_max_volume, get_global_max_volume, get_devices])
+    .plugin(tauri_plugin_notification::init())
+    .plugin(tauri_plugin_global_shortcut::Builder::new().with_handler(handle_shortcut).build())
+    .invoke_handler(tauri::generate_handler![
+      set_device_max_volume, set_default_device_max_volume, limit_default_device, set_device_max_volumes,
+      set_global_max_volume, set_device_max_volume_percent, set_global_max_volume_percent, set_form_factor_max_volume,
+      set_new_headphone_default_max, set_comms_max_volume, set_ducking, set_device_alias, add_managed_device,
+      remove_managed_device, set_managed_devices, set_device_ignore_global, set_device_limit_enabled, set_device_mute,
+      lock_device_volume, unlock_device_volume, override_device_max_volume, cancel_override, set_device_exceed_policy,
+      set_device_poll_interval, set_limiting_enabled, get_limiting_enabled, set_limit_mode, set_notify_on_enforce,
+      get_first_run, acknowledge_first_run, set_start_minimized, set_close_behavior, set_default_role,
+      set_com_apartment, simulate_apply, set_include_disabled_devices, set_intervals, set_max_step_per_tick, retry_init,
+      get_startup_error, get_startup_enforcement, health_check, refresh_devices, set_log_level, set_sort_order,
+      set_device_order, save_profile, delete_profile, set_profile_order, activate_profile, get_config, set_config,
+      patch_config, import_profile_file, export_profile_file, restore_backup, undo, redo, app_info, dump_diagnostics,
+      get_stats, recent_enforcements, reset_stats, set_volume_curve, set_shortcuts, volume_to_display,
+      display_to_volume, get_global_max_volume, get_default_device, get_all_endpoints, get_devices, get_device_sessions
+    ])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
 }
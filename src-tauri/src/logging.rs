@@ -0,0 +1,33 @@
+//! Console logging with a level that can be changed at runtime, so detailed output can be turned on
+//! while reproducing an issue without restarting the app.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much gets logged. Each level includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+  Error,
+  Warn,
+  /// Volume corrections.
+  #[default]
+  Info,
+  /// Every enforcement decision, including devices left alone.
+  Debug
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+  LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+  level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn log(level: LogLevel, message: impl AsRef<str>) {
+  if log_enabled(level) {
+    eprintln!("[{level:?}] {}", message.as_ref());
+  }
+}
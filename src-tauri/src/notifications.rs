@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+
+use crate::audio::{volume_to_percent, AudioDeviceConfig, Enforcement, EnforcementAction, LimitMode};
+use crate::errors::{emit_error, AppError, ErrorCode};
+
+/// Minimum time between two notifications about the same device.
+const NOTIFICATION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Tracks when each device was last notified about, so a device that keeps getting pushed over its limit
+/// doesn't produce a toast on every tick.
+#[derive(Default)]
+pub struct NotificationThrottle {
+  last_notified: HashMap<String, Instant>
+}
+
+impl NotificationThrottle {
+  fn allow(&mut self, device_id: &str, now: Instant) -> bool {
+    if self.last_notified.get(device_id).is_some_and(|last| now.duration_since(*last) < NOTIFICATION_INTERVAL) {
+      return false;
+    }
+    self.last_notified.insert(device_id.to_string(), now);
+    true
+  }
+}
+
+fn notification_body(enforcement: &Enforcement) -> String {
+  let percent = volume_to_percent(enforcement.max_volume);
+  match enforcement.action {
+    EnforcementAction::Corrected => format!("Capped {} at {percent}%.", enforcement.device_name),
+    EnforcementAction::Muted => format!("Muted {}, which went above its {percent}% limit.", enforcement.device_name),
+    EnforcementAction::Warned => format!("{} is above its {percent}% limit.", enforcement.device_name)
+  }
+}
+
+/// Shows a toast for each enforcement the config asks to be notified about.
+pub fn notify_enforcements(app_handle: &AppHandle, config: &AudioDeviceConfig, enforcements: &[Enforcement]) {
+  if !config.notify_on_enforce || (config.mode == LimitMode::WarnOnly && !config.notify_in_warn_only) {
+    return;
+  }
+
+  let now = Instant::now();
+  let throttle = app_handle.state::<Mutex<NotificationThrottle>>();
+  let mut throttle = throttle.lock().unwrap();
+  for enforcement in enforcements {
+    if !throttle.allow(&enforcement.device_id, now) {
+      continue;
+    }
+
+    let result = app_handle.notification()
+      .builder()
+      .title("Volume Limiter")
+      .body(notification_body(enforcement))
+      .show();
+    if let Err(err) = result {
+      emit_error(app_handle, AppError::new(ErrorCode::Notification, format!("Couldn't show notification: {err}")));
+    }
+  }
+}
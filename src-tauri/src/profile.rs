@@ -0,0 +1,46 @@
+//! Shareable `.vlprofile` files: the limits of one setup, wrapped in a versioned header so they can be
+//! checked before being applied on another machine.
+
+use crate::audio::AudioDeviceConfig;
+
+const PROFILE_KIND: &str = "volume-limiter-profile";
+/// Bumped whenever the payload changes in a way older versions of the app can't read.
+const PROFILE_VERSION: u32 = 1;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ProfileFile {
+  kind: String,
+  version: u32,
+  config: serde_json::Value
+}
+
+pub fn write_profile_file(path: &str, config: &AudioDeviceConfig) -> Result<(), String> {
+  // Which devices have been seen only means something on the machine that saw them.
+  let config = AudioDeviceConfig { seen_device_ids: Default::default(), ..config.clone() };
+  let profile = ProfileFile {
+    kind: PROFILE_KIND.to_string(),
+    version: PROFILE_VERSION,
+    config: serde_json::to_value(&config).map_err(|err| format!("{err}"))?
+  };
+
+  let json_str = serde_json::to_string_pretty(&profile).map_err(|err| format!("{err}"))?;
+  std::fs::write(path, json_str).map_err(|err| format!("Couldn't write profile: {err}"))
+}
+
+pub fn read_profile_file(path: &str) -> Result<AudioDeviceConfig, String> {
+  let json_str = std::fs::read_to_string(path).map_err(|err| format!("Couldn't read profile: {err}"))?;
+  let profile: ProfileFile = serde_json::from_str(&json_str).map_err(|err| format!("Not a profile file: {err}"))?;
+  if profile.kind != PROFILE_KIND {
+    return Err(format!("Not a profile file: unknown kind '{}'", profile.kind));
+  }
+  if profile.version != PROFILE_VERSION {
+    return Err(format!(
+      "Unsupported profile version {} (this version of the app reads version {PROFILE_VERSION})", profile.version
+    ));
+  }
+
+  let config: AudioDeviceConfig = serde_json::from_value(profile.config)
+    .map_err(|err| format!("Invalid profile: {err}"))?;
+  config.validate().map_err(|err| format!("Invalid profile: {err}"))?;
+  Ok(config)
+}
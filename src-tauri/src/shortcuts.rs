@@ -0,0 +1,80 @@
+//! Global keyboard shortcuts for nudging the global output limit without opening the window.
+
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+use tauri_plugin_global_shortcut::{GlobalShortcutExt, Shortcut, ShortcutEvent, ShortcutState};
+
+use crate::audio::{DataFlow, ShortcutConfig};
+use crate::errors::{emit_error, AppError, ErrorCode};
+use crate::tray::update_from_tray;
+
+/// The shortcuts currently registered, and the config they were registered from.
+#[derive(Default)]
+pub struct ShortcutBindings {
+  config: Option<ShortcutConfig>,
+  raise: Option<Shortcut>,
+  lower: Option<Shortcut>
+}
+
+pub fn parse_shortcut(accelerator: &str) -> Result<Shortcut, String> {
+  accelerator.parse().map_err(|err| format!("Invalid shortcut '{accelerator}': {err}"))
+}
+
+/// Registers the shortcuts in `config`, replacing the ones registered before. Does nothing if they're
+/// already registered from the same config.
+pub fn sync_shortcuts(app_handle: &AppHandle, config: &ShortcutConfig) {
+  let bindings = app_handle.state::<Mutex<ShortcutBindings>>();
+  let mut bindings = bindings.lock().unwrap();
+  if bindings.config.as_ref() == Some(config) {
+    return;
+  }
+
+  let global_shortcut = app_handle.global_shortcut();
+  for shortcut in [bindings.raise.take(), bindings.lower.take()].into_iter().flatten() {
+    if let Err(err) = global_shortcut.unregister(shortcut) {
+      emit_error(app_handle, AppError::new(ErrorCode::Shortcut, format!("Couldn't unregister shortcut: {err}")));
+    }
+  }
+  bindings.config = Some(config.clone());
+  if !config.enabled {
+    return;
+  }
+
+  for (accelerator, binding) in [(&config.raise, &mut bindings.raise), (&config.lower, &mut bindings.lower)] {
+    let result = parse_shortcut(accelerator).and_then(|shortcut| {
+      global_shortcut
+        .register(shortcut)
+        .map_err(|err| format!("Couldn't register shortcut '{accelerator}', another app may be using it: {err}"))?;
+      Ok(shortcut)
+    });
+    match result {
+      Ok(shortcut) => *binding = Some(shortcut),
+      Err(err) => emit_error(app_handle, AppError::new(ErrorCode::Shortcut, err))
+    }
+  }
+}
+
+/// Raises or lowers the global output limit by the configured step when one of our shortcuts is pressed.
+pub fn handle_shortcut(app_handle: &AppHandle, shortcut: &Shortcut, event: ShortcutEvent) {
+  if event.state() != ShortcutState::Pressed {
+    return;
+  }
+
+  let direction = {
+    let bindings = app_handle.state::<Mutex<ShortcutBindings>>();
+    let bindings = bindings.lock().unwrap();
+    match (bindings.raise.as_ref() == Some(shortcut), bindings.lower.as_ref() == Some(shortcut)) {
+      (true, _) => 1.0,
+      (_, true) => -1.0,
+      _ => return
+    }
+  };
+
+  update_from_tray(app_handle, "change global limit", move |controller| {
+    let step = controller.get_config().shortcuts.step;
+    let volume = controller.get_global_max_volume(DataFlow::Render) + direction * step;
+    // Rounded so repeated steps land on whole percentages instead of drifting.
+    let volume = ((volume * 100.0).round() / 100.0).clamp(0.0, 1.0);
+    controller.set_global_max_volume(DataFlow::Render, volume).map(|_| ())
+  });
+}
@@ -0,0 +1,87 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::audio::{Enforcement, EnforcementAction};
+
+/// How many of the latest enforcements are kept for `recent`.
+const RECENT_CAPACITY: usize = 200;
+
+/// How often a device has been pushed back down to its cap.
+#[derive(Clone, Default, serde::Serialize)]
+pub struct DeviceStats {
+  pub corrections: u64,
+  /// Milliseconds since the Unix epoch of the latest correction.
+  pub last_enforced_at: Option<u64>,
+  /// Largest amount the volume has been above the cap when it was corrected.
+  pub max_overshoot: f32,
+  /// Highest volume the device has been corrected from.
+  pub max_volume_seen: f32
+}
+
+/// A single enforcement, for a live activity feed.
+#[derive(Clone, serde::Serialize)]
+pub struct EnforcementRecord {
+  pub device_id: String,
+  /// Milliseconds since the Unix epoch.
+  pub timestamp: u64,
+  /// The volume the device was at.
+  pub from: f32,
+  /// The cap or locked volume it was brought to. A muted device's volume is left at `from`.
+  pub to: f32,
+  pub action: EnforcementAction
+}
+
+/// Per-device enforcement counters for the current session, and the latest enforcements.
+#[derive(Default)]
+pub struct EnforcementStats {
+  devices: HashMap<String, DeviceStats>,
+  recent: VecDeque<EnforcementRecord>
+}
+
+impl EnforcementStats {
+  pub fn record(&mut self, enforcement: &Enforcement) {
+    let now = now_millis();
+    let stats = self.devices.entry(enforcement.device_id.clone()).or_default();
+    stats.corrections += 1;
+    stats.last_enforced_at = now;
+    stats.max_overshoot = f32::max(stats.max_overshoot, enforcement.volume - enforcement.max_volume);
+    stats.max_volume_seen = f32::max(stats.max_volume_seen, enforcement.volume);
+
+    if self.recent.len() == RECENT_CAPACITY {
+      self.recent.pop_front();
+    }
+    self.recent.push_back(EnforcementRecord {
+      device_id: enforcement.device_id.clone(),
+      timestamp: now.unwrap_or_default(),
+      from: enforcement.volume,
+      to: enforcement.max_volume,
+      action: enforcement.action
+    });
+  }
+
+  pub fn get(&self) -> HashMap<String, DeviceStats> {
+    self.devices.clone()
+  }
+
+  /// Enforcements from the last `seconds`, oldest first. Older ones are dropped, as no window a caller
+  /// asks for later is expected to reach back further.
+  pub fn recent(&mut self, seconds: u64) -> Vec<EnforcementRecord> {
+    let cutoff = now_millis().unwrap_or_default().saturating_sub(seconds.saturating_mul(1000));
+    while self.recent.front().is_some_and(|record| record.timestamp < cutoff) {
+      self.recent.pop_front();
+    }
+    self.recent.iter().cloned().collect()
+  }
+
+  pub fn reset(&mut self) {
+    self.devices.clear();
+    self.recent.clear();
+  }
+}
+
+fn now_millis() -> Option<u64> {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .ok()
+    .map(|elapsed| elapsed.as_millis() as u64)
+}
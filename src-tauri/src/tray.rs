@@ -0,0 +1,190 @@
+use std::sync::Mutex;
+use tauri::menu::{CheckMenuItem, Menu, MenuItem, PredefinedMenuItem, Submenu};
+use tauri::tray::{MouseButton, TrayIconBuilder, TrayIconEvent};
+use tauri::{AppHandle, Manager, Runtime};
+
+use crate::audio::{
+  percent_to_volume, volume_to_percent, AudioController, AudioDeviceConfig, AudioDeviceInfo, DataFlow
+};
+use crate::errors::{emit_error, AppError, ErrorCode};
+use crate::{audio_thread, exit_app, update_config};
+
+const TRAY_ID: &str = "main";
+const DEVICE_ITEM_PREFIX: &str = "device:";
+const PRESET_ITEM_PREFIX: &str = "preset:";
+/// Longest device name shown in a menu label before it's cut off with an ellipsis.
+const MAX_LABEL_CHARS: usize = 40;
+/// Windows cuts tray tooltips off at 127 characters without any indication.
+const MAX_TOOLTIP_CHARS: usize = 127;
+
+/// What the tray menu was last built from, so it can be rebuilt when only the config or only the
+/// devices change.
+struct TrayState {
+  config: AudioDeviceConfig,
+  devices: Vec<AudioDeviceInfo>
+}
+
+pub fn show_main_window<R: Runtime>(app_handle: &AppHandle<R>) {
+  let window = app_handle.get_webview_window("main").unwrap();
+  window.show().unwrap();
+  window.set_focus().unwrap();
+}
+
+/// Shortens `label` to at most `max_chars` characters, ending it with an ellipsis if anything was cut.
+/// Only for display; `AudioDeviceInfo` keeps the full names.
+fn truncate_label(label: &str, max_chars: usize) -> String {
+  match label.chars().count() > max_chars {
+    true => format!("{}…", label.chars().take(max_chars - 1).collect::<String>().trim_end()),
+    false => label.to_string()
+  }
+}
+
+/// Shows the global output limit, so a change made with a shortcut can be checked without opening the window.
+fn tooltip_text(config: &AudioDeviceConfig) -> String {
+  let tooltip = format!("Volume Limiter ({}%)", volume_to_percent(config.render.global_max_volume));
+  truncate_label(&tooltip, MAX_TOOLTIP_CHARS)
+}
+
+fn build_devices_submenu(app_handle: &AppHandle, devices: &[AudioDeviceInfo]) -> tauri::Result<Submenu<tauri::Wry>> {
+  let submenu = Submenu::with_id(app_handle, "devices", "Devices", !devices.is_empty())?;
+  for device in devices {
+    let label = match device.limit_enabled {
+      true => format!("{} ({}%)", truncate_label(&device.display_name, MAX_LABEL_CHARS), device.max_volume_percent),
+      false => truncate_label(&device.display_name, MAX_LABEL_CHARS)
+    };
+    let item = CheckMenuItem::with_id(
+      app_handle, format!("{DEVICE_ITEM_PREFIX}{}", device.id), label, true, device.limit_enabled, None::<&str>
+    )?;
+    submenu.append(&item)?;
+  }
+  Ok(submenu)
+}
+
+/// Global limit presets, with the one matching the current global output limit checked.
+fn build_presets_submenu(app_handle: &AppHandle, config: &AudioDeviceConfig) -> tauri::Result<Submenu<tauri::Wry>> {
+  let current_percent = volume_to_percent(config.render.global_max_volume);
+  let submenu = Submenu::with_id(app_handle, "presets", "Global limit", !config.global_presets.is_empty())?;
+  for percent in &config.global_presets {
+    let item = CheckMenuItem::with_id(
+      app_handle, format!("{PRESET_ITEM_PREFIX}{percent}"), format!("{percent}%"), true, *percent == current_percent, None::<&str>
+    )?;
+    submenu.append(&item)?;
+  }
+  Ok(submenu)
+}
+
+/// Builds the tray menu. Checkable items reflect the config and devices, so the menu is rebuilt whenever
+/// either changes.
+fn build_menu(app_handle: &AppHandle, config: &AudioDeviceConfig, devices: &[AudioDeviceInfo]) -> tauri::Result<Menu<tauri::Wry>> {
+  let show_item = MenuItem::with_id(app_handle, "show", "Show", true, None::<&str>)?;
+  let presets_submenu = build_presets_submenu(app_handle, config)?;
+  let devices_submenu = build_devices_submenu(app_handle, devices)?;
+  let limiting_enabled_item = CheckMenuItem::with_id(
+    app_handle, "limiting_enabled", "Limiting enabled", true, config.limiting_enabled, None::<&str>
+  )?;
+  let start_minimized_item = CheckMenuItem::with_id(
+    app_handle, "start_minimized", "Start minimized to tray", true, config.start_minimized, None::<&str>
+  )?;
+  let include_disabled_item = CheckMenuItem::with_id(
+    app_handle, "include_disabled_devices", "Show disabled devices", true, config.include_disabled_devices, None::<&str>
+  )?;
+  let separator = PredefinedMenuItem::separator(app_handle)?;
+  let quit_item = MenuItem::with_id(app_handle, "quit", "Quit", true, None::<&str>)?;
+  Menu::with_items(app_handle, &[
+    &show_item, &presets_submenu, &devices_submenu, &limiting_enabled_item, &start_minimized_item, &include_disabled_item,
+    &separator, &quit_item
+  ])
+}
+
+/// Runs a config change picked from the tray or a shortcut. The config update rebuilds the menu with the
+/// new state.
+pub fn update_from_tray(
+  app_handle: &AppHandle,
+  action: &'static str,
+  update: impl FnOnce(&mut AudioController) -> Result<(), String> + Send + 'static
+) {
+  let app_handle = app_handle.clone();
+  tauri::async_runtime::spawn(async move {
+    let result = match audio_thread(&app_handle) {
+      Ok(audio) => update_config(&app_handle, &audio, update).await,
+      Err(err) => Err(err)
+    };
+    if let Err(err) = result {
+      emit_error(&app_handle, AppError::new(ErrorCode::Tray, format!("Couldn't {action}: {err}")));
+    }
+  });
+}
+
+pub fn create_tray(app_handle: &AppHandle, config: &AudioDeviceConfig) -> tauri::Result<()> {
+  app_handle.manage(Mutex::new(TrayState { config: config.clone(), devices: Vec::new() }));
+
+  TrayIconBuilder::with_id(TRAY_ID)
+    .menu(&build_menu(app_handle, config, &[])?)
+    .show_menu_on_left_click(false)
+    .icon(app_handle.default_window_icon().unwrap().clone())
+    .tooltip(tooltip_text(config))
+    .on_tray_icon_event(|tray, event| match event {
+      TrayIconEvent::DoubleClick { button: MouseButton::Left, .. } => show_main_window(tray.app_handle()),
+      _ => {}
+    })
+    .on_menu_event(|app_handle, event| match event.id.as_ref() {
+      "show" => show_main_window(app_handle),
+      "limiting_enabled" => update_from_tray(app_handle, "toggle limiting", |controller| {
+        controller.set_limiting_enabled(!controller.get_limiting_enabled());
+        Ok(())
+      }),
+      "start_minimized" => update_from_tray(app_handle, "toggle start minimized", |controller| {
+        controller.set_start_minimized(!controller.get_config().start_minimized);
+        Ok(())
+      }),
+      "include_disabled_devices" => update_from_tray(app_handle, "toggle disabled devices", |controller| {
+        controller.set_include_disabled_devices(!controller.get_config().include_disabled_devices);
+        Ok(())
+      }),
+      "quit" => exit_app(app_handle),
+      id => {
+        if let Some(device_id) = id.strip_prefix(DEVICE_ITEM_PREFIX) {
+          let device_id = device_id.to_string();
+          update_from_tray(app_handle, "toggle device limit", move |controller| {
+            let enabled = controller.get_device_limit_enabled(&device_id);
+            controller.set_device_limit_enabled(&device_id, !enabled);
+            Ok(())
+          });
+        } else if let Some(percent) = id.strip_prefix(PRESET_ITEM_PREFIX).and_then(|percent| percent.parse().ok()) {
+          update_from_tray(app_handle, "set global limit", move |controller| {
+            controller.set_global_max_volume(DataFlow::Render, percent_to_volume(percent)?).map(|_| ())
+          });
+        }
+      }
+    })
+    .build(app_handle)?;
+
+  Ok(())
+}
+
+fn rebuild_menu(app_handle: &AppHandle, state: &TrayState) -> Result<(), String> {
+  let Some(tray) = app_handle.tray_by_id(TRAY_ID) else {
+    return Ok(());
+  };
+  let menu = build_menu(app_handle, &state.config, &state.devices)
+    .map_err(|err| format!("Couldn't build tray menu: {err}"))?;
+  tray.set_menu(Some(menu)).map_err(|err| format!("Couldn't update tray menu: {err}"))?;
+  tray.set_tooltip(Some(tooltip_text(&state.config))).map_err(|err| format!("Couldn't update tray tooltip: {err}"))
+}
+
+/// Rebuilds the tray menu so it reflects `config` and `devices`.
+pub fn update_tray(app_handle: &AppHandle, config: &AudioDeviceConfig, devices: &[AudioDeviceInfo]) -> Result<(), String> {
+  let tray_state = app_handle.state::<Mutex<TrayState>>();
+  let mut tray_state = tray_state.lock().unwrap();
+  tray_state.config = config.clone();
+  tray_state.devices = devices.to_vec();
+  rebuild_menu(app_handle, &tray_state)
+}
+
+/// Rebuilds the tray menu with new devices, keeping the config it was last built with.
+pub fn update_tray_devices(app_handle: &AppHandle, devices: &[AudioDeviceInfo]) -> Result<(), String> {
+  let tray_state = app_handle.state::<Mutex<TrayState>>();
+  let mut tray_state = tray_state.lock().unwrap();
+  tray_state.devices = devices.to_vec();
+  rebuild_menu(app_handle, &tray_state)
+}
@@ -0,0 +1,88 @@
+//! Remembers the main window's size and position between runs.
+
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager, Monitor, PhysicalPosition, PhysicalSize};
+
+use crate::data::data_path;
+
+const WINDOW_STATE_FILE: &str = "window.json";
+/// How much of the window has to overlap a monitor, in both directions, for it to count as reachable.
+const MIN_VISIBLE_PX: i32 = 100;
+
+/// Outer position and inner size of the window, in physical pixels.
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+struct WindowGeometry {
+  x: i32,
+  y: i32,
+  width: u32,
+  height: u32
+}
+
+impl WindowGeometry {
+  fn overlaps(&self, monitor: &Monitor) -> bool {
+    let (position, size) = (monitor.position(), monitor.size());
+    let overlap_x = i32::min(self.x + self.width as i32, position.x + size.width as i32) - i32::max(self.x, position.x);
+    let overlap_y = i32::min(self.y + self.height as i32, position.y + size.height as i32) - i32::max(self.y, position.y);
+    overlap_x >= MIN_VISIBLE_PX && overlap_y >= MIN_VISIBLE_PX
+  }
+
+  /// Moves and, if it doesn't fit, shrinks the window so it lies entirely on `monitor`.
+  fn clamp_to(self, monitor: &Monitor) -> WindowGeometry {
+    let (position, size) = (monitor.position(), monitor.size());
+    let width = self.width.min(size.width);
+    let height = self.height.min(size.height);
+    WindowGeometry {
+      x: self.x.clamp(position.x, position.x + (size.width - width) as i32),
+      y: self.y.clamp(position.y, position.y + (size.height - height) as i32),
+      width,
+      height
+    }
+  }
+}
+
+fn window_state_path(app_handle: &AppHandle) -> Result<PathBuf, String> {
+  data_path(app_handle, WINDOW_STATE_FILE).map_err(|err| format!("{err}"))
+}
+
+/// Saves the main window's geometry. Call before the window is hidden, as a minimized window reports a
+/// position far off-screen and is skipped.
+pub fn save_window_geometry(app_handle: &AppHandle) -> Result<(), String> {
+  let Some(window) = app_handle.get_webview_window("main") else {
+    return Ok(());
+  };
+  if window.is_minimized().map_err(|err| format!("{err}"))? {
+    return Ok(());
+  }
+
+  let position = window.outer_position().map_err(|err| format!("Couldn't get window position: {err}"))?;
+  let size = window.inner_size().map_err(|err| format!("Couldn't get window size: {err}"))?;
+  let geometry = WindowGeometry { x: position.x, y: position.y, width: size.width, height: size.height };
+  let json_str = serde_json::to_string_pretty(&geometry).map_err(|err| format!("{err}"))?;
+  std::fs::write(window_state_path(app_handle)?, json_str).map_err(|err| format!("{err}"))
+}
+
+/// Moves the main window to where it was last saved. If that's no longer on any monitor, e.g. because the
+/// monitor was disconnected, the window is moved onto the primary monitor instead.
+pub fn restore_window_geometry(app_handle: &AppHandle) -> Result<(), String> {
+  let json_str = match std::fs::read_to_string(window_state_path(app_handle)?) {
+    Ok(json_str) => json_str,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(err) => return Err(format!("{err}"))
+  };
+  let mut geometry: WindowGeometry = serde_json::from_str(&json_str).map_err(|err| format!("{err}"))?;
+  let window = app_handle.get_webview_window("main").ok_or("Main window not found")?;
+
+  let monitors = window.available_monitors().map_err(|err| format!("Couldn't list monitors: {err}"))?;
+  if !monitors.iter().any(|monitor| geometry.overlaps(monitor)) {
+    let primary_monitor = window.primary_monitor().map_err(|err| format!("Couldn't get primary monitor: {err}"))?;
+    let Some(monitor) = primary_monitor.or_else(|| monitors.into_iter().next()) else {
+      return Ok(());
+    };
+    geometry = geometry.clamp_to(&monitor);
+  }
+
+  window.set_size(PhysicalSize::new(geometry.width, geometry.height))
+    .map_err(|err| format!("Couldn't set window size: {err}"))?;
+  window.set_position(PhysicalPosition::new(geometry.x, geometry.y))
+    .map_err(|err| format!("Couldn't set window position: {err}"))
+}
@@ -6,7 +6,45 @@ import Slider from 'rc-slider';
 interface DeviceInfo {
   id: string;
   name: string;
+  display_name: string;
+  name_available: boolean;
   max_volume: number;
+  effective_max_volume: number;
+  is_muted: boolean;
+  hardware_volume: boolean;
+  is_default: boolean;
+  is_default_comms: boolean;
+  connector: string | null;
+  icon_path: string | null;
+  icon_index: number | null;
+  channel_count: number;
+  channel_volumes: number[];
+}
+
+interface DeviceApplyResult {
+  device_id: string;
+  error: string | null;
+}
+
+interface DeviceChanges {
+  added: string[];
+  removed: string[];
+  unchanged: string[];
+  activated: string[];
+  default_changed: boolean;
+}
+
+interface DevicesUpdatedEvent {
+  devices: DeviceInfo[];
+  changes: DeviceChanges | null;
+}
+
+interface AppErrorEvent {
+  code: string;
+  message: string;
+  device_id: string | null;
+  recoverable: boolean;
+  count: number;
 }
 
 async function fetchDevices(): Promise<DeviceInfo[]> {
@@ -17,8 +55,12 @@ async function fetchGlobalMaxVolume(): Promise<number> {
   return invoke('get_global_max_volume');
 }
 
-function DeviceInfo(props: { device: DeviceInfo, onChangeMaxVolume: (id: string, volumePercentage: number) => void }) {
-  const { device, onChangeMaxVolume: onChangeVolume } = props;
+function DeviceInfo(props: {
+  device: DeviceInfo,
+  onChangeMaxVolume: (id: string, volumePercentage: number) => void,
+  onToggleMute?: (id: string, muted: boolean) => void
+}) {
+  const { device, onChangeMaxVolume: onChangeVolume, onToggleMute } = props;
   const volumePercentage = Math.floor(device.max_volume * 100);
 
   const handleMaxVolumeChange = (value: number | number[]) => {
@@ -27,7 +69,15 @@ function DeviceInfo(props: { device: DeviceInfo, onChangeMaxVolume: (id: string,
 
   return (
     <div className="device-info">
-      <h2 className="device-name">{device.name}</h2>
+      <h2 className="device-name">
+        {device.display_name}
+        {device.connector && <span className="device-badge">{device.connector}</span>}
+        {device.is_default && <span className="device-badge">Default</span>}
+        {device.is_default_comms && <span className="device-badge">Communications</span>}
+      </h2>
+      {!device.hardware_volume && (
+        <p className="device-warning">Volume is controlled in software; limits are enforced by the audio engine.</p>
+      )}
       <div className="device-volume">
         <Slider
           min={1}
@@ -38,15 +88,48 @@ function DeviceInfo(props: { device: DeviceInfo, onChangeMaxVolume: (id: string,
           onChange={handleMaxVolumeChange}
         />
         <label className="device-volume-label">{volumePercentage}</label>
+        {onToggleMute && (
+          <button className="device-mute-button" onClick={() => onToggleMute(device.id, !device.is_muted)}>
+            {device.is_muted ? 'Unmute' : 'Mute'}
+          </button>
+        )}
       </div>
       <input type="hidden" value={device.id} />
     </div>
   );
 }
 
+function GlobalMaxVolume(props: {
+  maxVolume: number,
+  onChangeMaxVolume: (volumePercentage: number) => void
+}) {
+  const { maxVolume, onChangeMaxVolume } = props;
+  const volumePercentage = Math.floor(maxVolume * 100);
+
+  return (
+    <div className="device-info">
+      <h2 className="device-name">Global Maximum Volume</h2>
+      <div className="device-volume">
+        <Slider
+          min={1}
+          max={100}
+          step={1}
+          className="device-volume-slider"
+          value={volumePercentage}
+          onChange={value => onChangeMaxVolume(value as number)}
+        />
+        <label className="device-volume-label">{volumePercentage}</label>
+      </div>
+    </div>
+  );
+}
+
 export default function App() {
   const [devices, setDevices] = useState<DeviceInfo[]>([]);
   const [globalMaxVolume, setGlobalMaxVolume] = useState<number>(1);
+  const [globalErrors, setGlobalErrors] = useState<string[]>([]);
+  const [limitingEnabled, setLimitingEnabled] = useState<boolean>(true);
+  const [startupError, setStartupError] = useState<string | null>(null);
 
   const onChangeDeviceMaxVolume = useCallback(async (deviceId: string, volumePercentage: number) => {
     const volume = volumePercentage / 100;
@@ -54,25 +137,65 @@ export default function App() {
     setDevices(await fetchDevices());
   }, []);
 
-  const onChangeGlobalMaxVolume = useCallback(async (_deviceId: string, volumePercentage: number) => {
+  const onToggleDeviceMute = useCallback(async (deviceId: string, muted: boolean) => {
+    await invoke('set_device_mute', { deviceId, muted });
+  }, []);
+
+  const onChangeGlobalMaxVolume = useCallback(async (volumePercentage: number) => {
     const volume = volumePercentage / 100;
-    await invoke('set_global_max_volume', { volume });
+    const results = await invoke<DeviceApplyResult[]>('set_global_max_volume', { volume });
     setGlobalMaxVolume(volume);
+    setGlobalErrors(results.filter(result => result.error !== null).map(result => result.error!));
+  }, []);
+
+  const onToggleLimiting = useCallback(async (enabled: boolean) => {
+    await invoke('set_limiting_enabled', { enabled });
+    setLimitingEnabled(enabled);
+  }, []);
+
+  const onRetryInit = useCallback(async () => {
+    try {
+      await invoke('retry_init');
+      setStartupError(null);
+    } catch (err) {
+      setStartupError(err as string);
+    }
   }, []);
 
   useEffect(() => {
+    invoke<string | null>('get_startup_error').then(setStartupError);
+    listen<string>('startup-error', event => setStartupError(event.payload));
+    listen<{ devices: DeviceInfo[] }>('startup-ready', event => setDevices(event.payload.devices));
     fetchDevices().then(setDevices);
     fetchGlobalMaxVolume().then(setGlobalMaxVolume);
-    listen<DeviceInfo[]>('devices-updated', event => setDevices(event.payload));
-    listen<string>('error', event => console.error(event.payload));
+    invoke<boolean>('get_limiting_enabled').then(setLimitingEnabled);
+    listen<{ limiting_enabled: boolean }>('config-changed', event => setLimitingEnabled(event.payload.limiting_enabled));
+    listen<DevicesUpdatedEvent>('devices-updated', event => setDevices(event.payload.devices));
+    listen<AppErrorEvent>('error', event => {
+      const { message, count } = event.payload;
+      console.error(count > 1 ? `${message} (repeated ${count} times)` : message);
+    });
   }, []);
 
   return (
     <div className="content">
-      <DeviceInfo device={{ id: "global", name: "Global Maximum Volume", max_volume: globalMaxVolume }} onChangeMaxVolume={onChangeGlobalMaxVolume} />
+      {startupError && (
+        <div className="device-warning">
+          <p>{startupError}</p>
+          <button className="device-mute-button" onClick={onRetryInit}>Retry</button>
+        </div>
+      )}
+      <label className="limiting-toggle">
+        <input type="checkbox" checked={limitingEnabled} onChange={e => onToggleLimiting(e.target.checked)} />
+        Limiting enabled
+      </label>
+      <GlobalMaxVolume maxVolume={globalMaxVolume} onChangeMaxVolume={onChangeGlobalMaxVolume} />
+      {globalErrors.map((error, i) => (
+        <p key={i} className="device-warning">{error}</p>
+      ))}
       <div className="divider" />
       {devices.map((device) => (
-        <DeviceInfo key={device.id} device={device} onChangeMaxVolume={onChangeDeviceMaxVolume} />
+        <DeviceInfo key={device.id} device={device} onChangeMaxVolume={onChangeDeviceMaxVolume} onToggleMute={onToggleDeviceMute} />
       ))}
     </div>
   );